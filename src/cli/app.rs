@@ -1,27 +1,77 @@
-use crate::cli::Context;
-use crate::output::Output;
+use crate::cli::{Context, Transport};
+use crate::eap::Package;
+use crate::output::{print_table, Level, Output};
 use clap::Clap;
 use crossterm::ErrorKind;
 use serde::Serialize;
-use std::io::Stdout;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use vapix::v3::application::{
+    ApplicationStatus, Architecture, ControlAction, LicenseStatus, UploadError,
+};
 use vapix::v3::Applications;
 
 /// Manage installed applications
-#[derive(Debug, Clap)]
+#[derive(Debug, Clone, Clap)]
 #[clap(setting = clap::AppSettings::VersionlessSubcommands)]
 pub struct App {
     #[clap(subcommand)]
     subcommand: Subcommand,
 }
 
-#[derive(Debug, Clap)]
+#[derive(Debug, Clone, Clap)]
 enum Subcommand {
     /// Display information about the application platform
     Info,
 
     /// List installed applications
     List,
+
+    /// Upload and install an application package
+    Install {
+        /// The application package (.eap) to install
+        #[clap(parse(from_os_str), value_hint = clap::ValueHint::FilePath)]
+        package: PathBuf,
+
+        /// Install even if the package does not appear to be compatible with the device
+        #[clap(short, long)]
+        force: bool,
+    },
+
+    /// Start an installed application
+    Start(Control),
+
+    /// Stop a running application
+    Stop(Control),
+
+    /// Restart an application
+    Restart(Control),
+
+    /// Remove an installed application
+    #[clap(alias = "uninstall")]
+    Remove(Control),
+}
+
+#[derive(Debug, Clone, Clap)]
+struct Control {
+    /// The name of the application package, as shown by `axctl app list`
+    package: String,
+
+    /// Wait until the application reaches the requested state
+    #[clap(short, long)]
+    wait: bool,
+
+    /// The maximum number of seconds to wait
+    #[clap(long, default_value = "30")]
+    timeout: u64,
+}
+
+impl Control {
+    fn wait_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.timeout)).filter(|_| self.wait)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -32,6 +82,14 @@ pub enum Error {
     VapixCallFailed(vapix::Error),
     #[error("device not supported, since it does not provide the applications interface")]
     DeviceNotSupported,
+    #[error("error reading {0:?}: {1}")]
+    PackageReadError(PathBuf, std::io::Error),
+    #[error("failed to install {0:?}")]
+    InstallFailed(PathBuf),
+    #[error("no application named {0:?} is installed on the device")]
+    UnknownPackage(String),
+    #[error("timed out waiting for {0:?} to be {1}")]
+    WaitTimedOut(String, &'static str),
 }
 
 #[derive(Serialize)]
@@ -52,9 +110,8 @@ impl<'a, T: vapix::Transport> From<&'a vapix::v3::application::Applications<'a,
 }
 
 impl Output for Info {
-    fn print(&self, stdout: &mut Stdout) -> Result<(), ErrorKind> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
         use crossterm::{queue, style::*};
-        use std::io::Write;
 
         fn intensity<T>(o: &Option<T>) -> SetAttribute {
             SetAttribute(if o.is_some() {
@@ -95,6 +152,443 @@ impl Output for Info {
     }
 }
 
+#[derive(Clone, Serialize)]
+struct Application {
+    name: String,
+    nice_name: String,
+    vendor: String,
+    version: String,
+    status: ApplicationStatus,
+    license: LicenseStatus,
+}
+
+impl From<vapix::v3::application::Application> for Application {
+    fn from(a: vapix::v3::application::Application) -> Self {
+        Self {
+            name: a.name,
+            nice_name: a.nice_name,
+            vendor: a.vendor,
+            version: a.version,
+            status: a.status,
+            license: a.license,
+        }
+    }
+}
+
+fn status_name(status: ApplicationStatus) -> &'static str {
+    match status {
+        ApplicationStatus::Running => "running",
+        ApplicationStatus::Stopped => "stopped",
+        ApplicationStatus::Idle => "idle",
+    }
+}
+
+fn license_name(license: LicenseStatus) -> &'static str {
+    match license {
+        LicenseStatus::Valid => "valid",
+        LicenseStatus::Invalid => "invalid",
+        LicenseStatus::Missing => "missing",
+        LicenseStatus::Custom => "custom",
+        LicenseStatus::None => "none",
+    }
+}
+
+impl Output for Application {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        ApplicationList(vec![self.clone()]).print(stdout)
+    }
+}
+
+/// A table of installed applications.
+#[derive(Serialize)]
+struct ApplicationList(Vec<Application>);
+
+impl Output for ApplicationList {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::style::Color;
+
+        let rows: Vec<[&str; 6]> = self
+            .0
+            .iter()
+            .map(|a| {
+                [
+                    a.name.as_str(),
+                    a.nice_name.as_str(),
+                    a.vendor.as_str(),
+                    a.version.as_str(),
+                    status_name(a.status),
+                    license_name(a.license),
+                ]
+            })
+            .collect();
+
+        print_table(
+            stdout,
+            &[
+                "NAME",
+                "NICE NAME",
+                "VENDOR",
+                "VERSION",
+                "STATUS",
+                "LICENSE",
+            ],
+            &rows,
+            |row, column| {
+                let app = &self.0[row];
+                match column {
+                    0 => Color::Cyan,
+                    4 => match app.status {
+                        ApplicationStatus::Running => Color::Green,
+                        ApplicationStatus::Stopped => Color::Yellow,
+                        ApplicationStatus::Idle => Color::DarkGrey,
+                    },
+                    5 => match app.license {
+                        LicenseStatus::Valid | LicenseStatus::Custom => Color::Green,
+                        LicenseStatus::Invalid => Color::Red,
+                        LicenseStatus::Missing | LicenseStatus::None => Color::DarkGrey,
+                    },
+                    _ => Color::Reset,
+                }
+            },
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Verdict {
+    Installed,
+    ValidationFailed,
+    ArchitectureMismatch,
+    FirmwareMismatch,
+    Failed,
+}
+
+#[derive(Serialize)]
+struct InstallResult {
+    package: PathBuf,
+    name: Option<String>,
+    version: Option<String>,
+    verdict: Verdict,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl InstallResult {
+    fn new(
+        package: PathBuf,
+        eap: Option<&Package>,
+        verdict: Verdict,
+        message: Option<String>,
+    ) -> Self {
+        Self {
+            package,
+            name: eap.and_then(|p| p.name()).map(|s| s.to_owned()),
+            version: eap.and_then(|p| p.version()),
+            verdict,
+            message,
+        }
+    }
+}
+
+impl Output for InstallResult {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        let name = match (&self.name, &self.version) {
+            (Some(name), Some(version)) => format!("{} {}", name, version),
+            (Some(name), None) => name.clone(),
+            _ => self.package.display().to_string(),
+        };
+
+        let (color, verdict) = match self.verdict {
+            Verdict::Installed => (Color::Green, "installed"),
+            Verdict::ValidationFailed => (Color::Red, "failed validation"),
+            Verdict::ArchitectureMismatch | Verdict::FirmwareMismatch => {
+                (Color::Red, "is not compatible with this device")
+            }
+            Verdict::Failed => (Color::Red, "failed to install"),
+        };
+
+        queue!(
+            stdout,
+            Print(" => "),
+            SetAttribute(Attribute::Bold),
+            Print(name),
+            SetAttribute(Attribute::NormalIntensity),
+            Print(" "),
+            SetForegroundColor(color),
+            Print(verdict),
+            ResetColor,
+        )?;
+        if let Some(message) = &self.message {
+            queue!(stdout, Print(": "), Print(message))?;
+        }
+        queue!(stdout, Print("\n"))
+    }
+
+    fn level(&self) -> Level {
+        match self.verdict {
+            Verdict::Installed => Level::Info,
+            _ => Level::Error,
+        }
+    }
+}
+
+/// Map a `package.conf` `APPTYPE` to the corresponding device architecture.
+fn architecture_for_app_type(app_type: &str) -> Option<Architecture> {
+    match app_type {
+        "aarch64" => Some(Architecture::Aarch64),
+        "armv7" => Some(Architecture::Armv7),
+        "armv7hf" => Some(Architecture::Armv7Hf),
+        "mipsisa32r2el" => Some(Architecture::Mips),
+        _ => None,
+    }
+}
+
+/// Compare dotted version strings like `"2.13"` component-wise.
+fn version_at_least(have: &str, need: &str) -> bool {
+    fn parse(v: &str) -> Vec<u32> {
+        v.split('.')
+            .map(|c| c.trim().parse().unwrap_or(0))
+            .collect()
+    }
+
+    let (mut have, mut need) = (parse(have), parse(need));
+    let len = have.len().max(need.len());
+    have.resize(len, 0);
+    need.resize(len, 0);
+    have >= need
+}
+
+/// Check whether `eap` can run on the device, explaining why not if it can't.
+///
+/// Anything we can't determine -- a device which doesn't report its architecture, a package with
+/// no `APPTYPE` because it contains only scripts -- is given the benefit of the doubt.
+fn check_compatibility(
+    applications: &Applications<'_, Transport>,
+    eap: &Package,
+) -> Result<(), (Verdict, String)> {
+    let device_architecture = applications.architecture();
+    let package_architecture = eap.app_type().and_then(architecture_for_app_type);
+
+    if let (Some(device), Some(package)) = (device_architecture, package_architecture) {
+        if device != package {
+            let device = match applications.soc() {
+                Some(soc) => format!("{} ({})", soc.display_name(), device.display_name()),
+                None => device.display_name().to_owned(),
+            };
+            return Err((
+                Verdict::ArchitectureMismatch,
+                format!(
+                    "package is built for {}, but the device is {}; use --force to install anyway",
+                    eap.app_type().unwrap_or_default(),
+                    device
+                ),
+            ));
+        }
+    }
+
+    if let Some(required) = eap.required_embedded_development_version() {
+        let available = applications.embedded_development_version();
+        if !version_at_least(available, required) {
+            return Err((
+                Verdict::FirmwareMismatch,
+                format!(
+                    "package requires embedded development version {}, but the device{} provides {}; \
+                     use --force to install anyway",
+                    required,
+                    applications
+                        .firmware_version()
+                        .map(|v| format!(" (firmware {})", v))
+                        .unwrap_or_default(),
+                    available
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) async fn install(
+    context: &mut Context,
+    applications: &Applications<'_, Transport>,
+    path: PathBuf,
+    force: bool,
+) -> Result<(), Error> {
+    let bytes = std::fs::read(&path).map_err(|e| Error::PackageReadError(path.clone(), e))?;
+
+    let eap = match Package::parse(&bytes) {
+        Ok(eap) => eap,
+        Err(e) => {
+            context.output(InstallResult::new(
+                path.clone(),
+                None,
+                Verdict::ValidationFailed,
+                Some(e.to_string()),
+            ))?;
+            return Err(Error::InstallFailed(path));
+        }
+    };
+
+    if !force {
+        if let Err((verdict, message)) = check_compatibility(applications, &eap) {
+            context.output(InstallResult::new(
+                path.clone(),
+                Some(&eap),
+                verdict,
+                Some(message),
+            ))?;
+            return Err(Error::InstallFailed(path));
+        }
+    }
+
+    let (verdict, message) = match applications.upload(&bytes).await {
+        Ok(()) => (Verdict::Installed, None),
+        Err(vapix::Error::ApplicationUploadFailed(UploadError::VerificationFailed)) => (
+            Verdict::ValidationFailed,
+            Some("the device rejected the package".to_owned()),
+        ),
+        Err(vapix::Error::ApplicationUploadFailed(UploadError::IncompatibleArchitecture)) => (
+            Verdict::ArchitectureMismatch,
+            Some("the package was built for a different architecture".to_owned()),
+        ),
+        Err(e) => (Verdict::Failed, Some(e.to_string())),
+    };
+
+    context.output(InstallResult::new(
+        path.clone(),
+        Some(&eap),
+        verdict,
+        message,
+    ))?;
+
+    match verdict {
+        Verdict::Installed => Ok(()),
+        _ => Err(Error::InstallFailed(path)),
+    }
+}
+
+#[derive(Serialize)]
+struct ControlResult {
+    package: String,
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<ApplicationStatus>,
+}
+
+impl Output for ControlResult {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            Print(" => "),
+            SetAttribute(Attribute::Bold),
+            Print(&self.package),
+            SetAttribute(Attribute::NormalIntensity),
+            Print(" "),
+            Print(self.action),
+        )?;
+        if let Some(status) = self.status {
+            queue!(stdout, Print(format!(" (now {})", status_name(status))))?;
+        }
+        queue!(stdout, Print("\n"))
+    }
+}
+
+/// Start, stop, restart or remove an application, waiting up to `wait` for it to get there if
+/// given.
+pub(super) async fn control(
+    context: &mut Context,
+    applications: &Applications<'_, Transport>,
+    action: ControlAction,
+    package: String,
+    wait: Option<Duration>,
+) -> Result<(), Error> {
+    if current_status(applications, &package).await?.is_none() {
+        return Err(Error::UnknownPackage(package));
+    }
+
+    applications
+        .control(&package, action)
+        .await
+        .map_err(Error::VapixCallFailed)?;
+
+    let status = match wait {
+        Some(timeout) => wait_for(applications, &package, action, timeout).await?,
+        None => None,
+    };
+
+    let action = match action {
+        ControlAction::Start => "started",
+        ControlAction::Stop => "stopped",
+        ControlAction::Restart => "restarted",
+        ControlAction::Remove => "removed",
+    };
+
+    context.output(ControlResult {
+        package,
+        action,
+        status,
+    })?;
+
+    Ok(())
+}
+
+/// Find the status of `package`, or `None` if it is not installed.
+pub(super) async fn current_status(
+    applications: &Applications<'_, Transport>,
+    package: &str,
+) -> Result<Option<ApplicationStatus>, Error> {
+    Ok(applications
+        .list()
+        .await
+        .map_err(Error::VapixCallFailed)?
+        .into_iter()
+        .find(|a| a.name == package)
+        .map(|a| a.status))
+}
+
+/// Poll until `package` reaches the state requested by `action`, returning its final status.
+async fn wait_for(
+    applications: &Applications<'_, Transport>,
+    package: &str,
+    action: ControlAction,
+    timeout: Duration,
+) -> Result<Option<ApplicationStatus>, Error> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let status = current_status(applications, package).await?;
+
+        let (done, desired) = match action {
+            // The restart has been requested by now, and a device may finish it before the first
+            // poll, so an application which is running again counts as restarted
+            ControlAction::Start | ControlAction::Restart => {
+                (status == Some(ApplicationStatus::Running), "running")
+            }
+            ControlAction::Stop => (
+                matches!(
+                    status,
+                    Some(ApplicationStatus::Stopped) | Some(ApplicationStatus::Idle)
+                ),
+                "stopped",
+            ),
+            ControlAction::Remove => (status.is_none(), "removed"),
+        };
+
+        if done {
+            return Ok(status);
+        } else if Instant::now() >= deadline {
+            return Err(Error::WaitTimedOut(package.to_owned(), desired));
+        }
+
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+    }
+}
+
 impl App {
     pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
         let client = context.client();
@@ -108,7 +602,71 @@ impl App {
             Subcommand::Info => {
                 context.output(Info::from(&applications))?;
             }
-            Subcommand::List => todo!(),
+            Subcommand::List => {
+                let mut list: Vec<Application> = applications
+                    .list()
+                    .await
+                    .map_err(Error::VapixCallFailed)?
+                    .into_iter()
+                    .map(Application::from)
+                    .collect();
+                list.sort_by(|a, b| a.name.cmp(&b.name));
+
+                if context.is_tty {
+                    context.output(ApplicationList(list))?;
+                } else {
+                    for app in list {
+                        context.output(app)?;
+                    }
+                }
+            }
+            Subcommand::Install { package, force } => {
+                install(context, &applications, package, force).await?;
+            }
+            Subcommand::Start(args) => {
+                let wait = args.wait_timeout();
+                control(
+                    context,
+                    &applications,
+                    ControlAction::Start,
+                    args.package,
+                    wait,
+                )
+                .await?;
+            }
+            Subcommand::Stop(args) => {
+                let wait = args.wait_timeout();
+                control(
+                    context,
+                    &applications,
+                    ControlAction::Stop,
+                    args.package,
+                    wait,
+                )
+                .await?;
+            }
+            Subcommand::Restart(args) => {
+                let wait = args.wait_timeout();
+                control(
+                    context,
+                    &applications,
+                    ControlAction::Restart,
+                    args.package,
+                    wait,
+                )
+                .await?;
+            }
+            Subcommand::Remove(args) => {
+                let wait = args.wait_timeout();
+                control(
+                    context,
+                    &applications,
+                    ControlAction::Remove,
+                    args.package,
+                    wait,
+                )
+                .await?;
+            }
         }
 
         Ok(())
@@ -0,0 +1,484 @@
+use super::app;
+use super::param::{self, Change};
+use crate::cli::{Context, Transport};
+use crate::eap::Package;
+use crate::output::Output;
+use crate::site::{self, Site, State};
+use clap::Clap;
+use crossterm::ErrorKind;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use vapix::v3::application::{ApplicationStatus, ControlAction};
+use vapix::v3::Applications;
+
+/// How long to wait for an application to start, stop or go away.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Make devices match a site file, changing only what differs
+#[derive(Debug, Clone, Clap)]
+pub struct Apply {
+    /// The site file declaring parameters and applications
+    #[clap(parse(from_os_str), value_hint = clap::ValueHint::FilePath)]
+    site: PathBuf,
+
+    /// Show what differs without changing anything
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// Install packages even if they do not appear to be compatible with the device
+    #[clap(short, long)]
+    force: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error writing to terminal: {0}")]
+    TerminalError(#[from] crossterm::ErrorKind),
+    #[error("{0}")]
+    SiteError(#[from] site::Error),
+    #[error("VAPIX call failed: {0}")]
+    VapixCallFailed(#[from] vapix::Error),
+    #[error("{0}")]
+    AppError(#[from] app::Error),
+    #[error("{0}")]
+    ParamError(#[from] param::Error),
+    #[error("device not supported, since it does not provide the applications interface")]
+    DeviceNotSupported,
+    #[error("{0} needs to be installed, but the site file gives no package for it")]
+    NoPackage(String),
+    #[error("error reading {0:?}: {1}")]
+    PackageReadError(PathBuf, std::io::Error),
+    #[error("{0:?} is not a usable application package: {1}")]
+    PackageError(PathBuf, crate::eap::Error),
+    #[error("{0:?} contains {1:?}, not {2:?}")]
+    PackageMismatch(PathBuf, String, String),
+    #[error("{0:?} contains version {1}, but the site file asks for {2}")]
+    VersionMismatch(PathBuf, String, String),
+}
+
+/// One difference between a device and the site file, and what to do about it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Step {
+    Remove {
+        application: String,
+        version: String,
+    },
+    Install {
+        application: String,
+        version: Option<String>,
+        package: PathBuf,
+        /// The version being replaced, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        installed: Option<String>,
+    },
+    Set(Change),
+    Start {
+        application: String,
+    },
+    Stop {
+        application: String,
+    },
+}
+
+impl Step {
+    /// The order in which steps are taken: applications first, so that their parameters exist,
+    /// and starting or stopping them last, so that they run with their new parameters.
+    fn order(&self) -> u8 {
+        match self {
+            Step::Remove { .. } => 0,
+            Step::Install { .. } => 1,
+            Step::Set(_) => 2,
+            Step::Start { .. } | Step::Stop { .. } => 3,
+        }
+    }
+}
+
+impl Output for Step {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        let (color, symbol, text) = match self {
+            Step::Set(change) => return change.print(stdout),
+            Step::Remove {
+                application,
+                version,
+            } => (
+                Color::Red,
+                "-",
+                format!("remove {} {}", application, version),
+            ),
+            Step::Install {
+                application,
+                version,
+                package,
+                installed,
+            } => {
+                let version = version.as_deref().unwrap_or("(unknown version)");
+                match installed {
+                    Some(installed) => (
+                        Color::Yellow,
+                        "~",
+                        format!(
+                            "upgrade {} {} => {} from {}",
+                            application,
+                            installed,
+                            version,
+                            package.display()
+                        ),
+                    ),
+                    None => (
+                        Color::Green,
+                        "+",
+                        format!(
+                            "install {} {} from {}",
+                            application,
+                            version,
+                            package.display()
+                        ),
+                    ),
+                }
+            }
+            Step::Start { application } => (Color::Yellow, "~", format!("start {}", application)),
+            Step::Stop { application } => (Color::Yellow, "~", format!("stop {}", application)),
+        };
+
+        queue!(
+            stdout,
+            SetForegroundColor(color),
+            Print(format!("  {} ", symbol)),
+            ResetColor,
+            Print(text),
+            Print("\n"),
+        )
+    }
+}
+
+/// The differences between a device and the site file.
+#[derive(Serialize)]
+struct Plan {
+    site: PathBuf,
+    steps: Vec<Step>,
+    dry_run: bool,
+}
+
+impl Output for Plan {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        if self.steps.is_empty() {
+            return queue!(
+                stdout,
+                SetForegroundColor(Color::Green),
+                Print(format!(" => no drift from {}\n", self.site.display())),
+                ResetColor,
+            );
+        }
+
+        queue!(
+            stdout,
+            Print(format!(
+                " => {} difference{} from {}{}:\n",
+                self.steps.len(),
+                if self.steps.len() == 1 { "" } else { "s" },
+                self.site.display(),
+                if self.dry_run { ", not applying" } else { "" },
+            )),
+        )?;
+        for step in &self.steps {
+            step.print(stdout)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Applied {
+    site: PathBuf,
+    changes: usize,
+}
+
+impl Output for Applied {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Green),
+            Print(format!(
+                " => applied {} change{}; the device now matches {}\n",
+                self.changes,
+                if self.changes == 1 { "" } else { "s" },
+                self.site.display()
+            )),
+            ResetColor,
+        )
+    }
+}
+
+/// Read a package's version, checking that it's the application it's meant to be.
+fn package_version(path: &Path, application: &str) -> Result<Option<String>, Error> {
+    let bytes = std::fs::read(path).map_err(|e| Error::PackageReadError(path.into(), e))?;
+    let package = Package::parse(&bytes).map_err(|e| Error::PackageError(path.into(), e))?;
+    match package.name() {
+        Some(name) if name != application => Err(Error::PackageMismatch(
+            path.into(),
+            name.to_owned(),
+            application.to_owned(),
+        )),
+        _ => Ok(package.version()),
+    }
+}
+
+async fn plan_applications(
+    applications: &Applications<'_, Transport>,
+    desired: &BTreeMap<String, site::Application>,
+) -> Result<Vec<Step>, Error> {
+    let installed = applications.list().await?;
+    let mut steps = Vec::new();
+
+    for (name, want) in desired {
+        let current = installed.iter().find(|a| &a.name == name);
+
+        if want.state == State::Absent {
+            if let Some(current) = current {
+                steps.push(Step::Remove {
+                    application: name.clone(),
+                    version: current.version.clone(),
+                });
+            }
+            continue;
+        }
+
+        let version = match (&want.package, &want.version) {
+            (Some(path), wanted) => match (package_version(path, name)?, wanted) {
+                (Some(found), Some(wanted)) if &found != wanted => {
+                    return Err(Error::VersionMismatch(path.clone(), found, wanted.clone()))
+                }
+                (found, wanted) => found.or_else(|| wanted.clone()),
+            },
+            (None, wanted) => wanted.clone(),
+        };
+
+        let needs_install = match (current, &version) {
+            (None, _) => true,
+            (Some(current), Some(version)) => &current.version != version,
+            (Some(_), None) => false,
+        };
+        if needs_install {
+            steps.push(Step::Install {
+                application: name.clone(),
+                version,
+                package: want
+                    .package
+                    .clone()
+                    .ok_or_else(|| Error::NoPackage(name.clone()))?,
+                installed: current.map(|c| c.version.clone()),
+            });
+        }
+
+        // A freshly installed application may or may not start by itself, so plan for either and
+        // check again once it's installed
+        let running = current.map(|c| c.status) == Some(ApplicationStatus::Running);
+        match want.state {
+            State::Running if needs_install || !running => steps.push(Step::Start {
+                application: name.clone(),
+            }),
+            State::Stopped if needs_install || running => steps.push(Step::Stop {
+                application: name.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    Ok(steps)
+}
+
+/// The top-level group of a parameter, which for an application's parameters is its name.
+fn group_of(name: &str) -> &str {
+    name.split('.').next().unwrap_or(name)
+}
+
+/// Compare assignments with the device, failing if it lacks any of the parameters.
+async fn diff(
+    client: &vapix::Client<Transport>,
+    assignments: &[(String, String)],
+) -> Result<Vec<Change>, Error> {
+    if assignments.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Ask for whole top-level groups, which keeps the request short however many parameters there
+    // are
+    let mut groups: Vec<&str> = assignments.iter().map(|(name, _)| group_of(name)).collect();
+    groups.sort_unstable();
+    groups.dedup();
+
+    let current = param::list(client, &groups).await?;
+    Ok(param::changes(&current, assignments)?)
+}
+
+/// Plan parameter changes.
+///
+/// The parameters of applications which are yet to be installed or upgraded may not exist yet, so
+/// they're planned as changes from nothing, and compared again once the applications are installed.
+async fn plan_parameters(
+    client: &vapix::Client<Transport>,
+    desired: &BTreeMap<String, String>,
+    pending: &[&str],
+) -> Result<Vec<Change>, Error> {
+    let (later, now): (Vec<_>, Vec<_>) = desired
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .partition(|(name, _)| pending.contains(&group_of(name)));
+
+    let mut changes = diff(client, &now).await?;
+    changes.extend(later.into_iter().map(|(name, new)| Change {
+        name,
+        old: None,
+        new,
+    }));
+    Ok(changes)
+}
+
+impl Apply {
+    /// Warn of sections in the site file for devices which aren't among `devices`, since they're
+    /// likely to be typos. A site file which can't be loaded is left for `invoke()` to report.
+    pub fn warn_of_unmatched_devices(&self, devices: &[&str]) {
+        if let Ok(site) = Site::load(&self.site) {
+            for name in site.unmatched_devices(devices) {
+                eprintln!(
+                    " => warning: {} has a section for {:?}, which matches none of the targeted devices",
+                    self.site.display(),
+                    name
+                );
+            }
+        }
+    }
+
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        let site = Site::load(&self.site)?;
+        let desired = site.desired(context.target.name.as_deref())?;
+
+        let client = context.client();
+        let applications = if desired.applications.is_empty() {
+            None
+        } else {
+            Some(
+                client
+                    .applications()
+                    .await?
+                    .ok_or(Error::DeviceNotSupported)?,
+            )
+        };
+
+        let mut steps = match &applications {
+            Some(applications) => plan_applications(applications, &desired.applications).await?,
+            None => Vec::new(),
+        };
+        // An upgrade may add parameters which the version still installed doesn't have
+        let pending: Vec<&str> = steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Install { application, .. } => Some(application.as_str()),
+                _ => None,
+            })
+            .collect();
+        let parameters = plan_parameters(&client, &desired.parameters, &pending).await?;
+        steps.extend(parameters.into_iter().map(Step::Set));
+        steps.sort_by_key(Step::order);
+
+        let changes = steps.len();
+        context.output(Plan {
+            site: self.site.clone(),
+            steps: steps.clone(),
+            dry_run: self.dry_run,
+        })?;
+        if self.dry_run || steps.is_empty() {
+            return Ok(());
+        }
+
+        let parameters: Vec<Change> = steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Set(change) => Some(change.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut parameters_updated = false;
+
+        for step in steps {
+            let applications = || applications.as_ref().ok_or(Error::DeviceNotSupported);
+            match step {
+                Step::Remove { application, .. } => {
+                    app::control(
+                        context,
+                        applications()?,
+                        ControlAction::Remove,
+                        application,
+                        Some(CONTROL_TIMEOUT),
+                    )
+                    .await?
+                }
+                Step::Install { package, .. } => {
+                    app::install(context, applications()?, package, self.force).await?
+                }
+                Step::Set(_) if parameters_updated => {}
+                Step::Set(_) => {
+                    // Applications installed or upgraded just now have their parameters at last,
+                    // and some may already have the values we want
+                    let parameters = if parameters.iter().any(|c| c.old.is_none()) {
+                        let assignments: Vec<(String, String)> = parameters
+                            .iter()
+                            .map(|c| (c.name.clone(), c.new.clone()))
+                            .collect();
+                        diff(&client, &assignments).await?
+                    } else {
+                        parameters.clone()
+                    };
+                    if !parameters.is_empty() {
+                        param::update(&client, &parameters).await?;
+                    }
+                    parameters_updated = true;
+                }
+                // Some applications start by themselves once installed
+                Step::Start { application }
+                    if app::current_status(applications()?, &application).await?
+                        == Some(ApplicationStatus::Running) => {}
+                Step::Start { application } => {
+                    app::control(
+                        context,
+                        applications()?,
+                        ControlAction::Start,
+                        application,
+                        Some(CONTROL_TIMEOUT),
+                    )
+                    .await?
+                }
+                Step::Stop { application }
+                    if app::current_status(applications()?, &application).await?
+                        != Some(ApplicationStatus::Running) => {}
+                Step::Stop { application } => {
+                    app::control(
+                        context,
+                        applications()?,
+                        ControlAction::Stop,
+                        application,
+                        Some(CONTROL_TIMEOUT),
+                    )
+                    .await?
+                }
+            }
+        }
+
+        context.output(Applied {
+            site: self.site,
+            changes,
+        })?;
+        Ok(())
+    }
+}
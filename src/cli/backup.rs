@@ -0,0 +1,303 @@
+use super::cp;
+use super::exec::quote;
+use super::param;
+use super::shell::{self, end_session, start_session, Session};
+use crate::cli::Context;
+use crate::output::Output;
+use clap::Clap;
+use crossterm::terminal::{Clear, ClearType};
+use crossterm::ErrorKind;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// Where ACAP applications are installed, each in a directory named after it.
+pub(super) const PACKAGES: &str = "/usr/local/packages";
+
+/// What a backup contains, besides the files under `FILES`.
+pub(super) const MANIFEST: &str = "manifest.json";
+pub(super) const PARAMETERS: &str = "parameters.json";
+pub(super) const APPLICATIONS: &str = "applications.json";
+pub(super) const FILES: &str = "files";
+
+/// The parameters which say which device this is and what it runs.
+pub(super) const MODEL: &str = "Brand.ProdNbr";
+pub(super) const FIRMWARE_VERSION: &str = "Properties.Firmware.Version";
+
+/// Back up a device's parameters, applications and their files as a gzipped tar
+#[derive(Debug, Clone, Clap)]
+pub struct Backup {
+    /// Where to write the backup, instead of stdout
+    #[clap(short, long, parse(from_os_str), value_hint = clap::ValueHint::FilePath)]
+    output: Option<PathBuf>,
+
+    /// Also back up a file or directory on the device (may be repeated)
+    #[clap(short, long, number_of_values = 1)]
+    include: Vec<String>,
+
+    /// Leave out files, which avoids starting a shell on the device
+    #[clap(long, conflicts_with = "include")]
+    no_files: bool,
+
+    /// The remote port on which to establish a shell-over-SSL connection
+    #[clap(short, long)]
+    port: Option<u16>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error writing to terminal: {0}")]
+    TerminalError(#[from] crossterm::ErrorKind),
+    #[error("VAPIX call failed: {0}")]
+    VapixCallFailed(#[from] vapix::Error),
+    #[error("{0}")]
+    ShellError(#[from] shell::Error),
+    #[error("{0}")]
+    CpError(#[from] cp::Error),
+    #[error("refusing to write a backup to a terminal; redirect stdout or use --output")]
+    StdoutIsTerminal,
+    #[error("error writing {0:?}: {1}")]
+    WriteError(PathBuf, std::io::Error),
+    #[error("error writing to stdout: {0}")]
+    OutputError(std::io::Error),
+}
+
+/// Which device a backup came from, and which files it holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    /// The version of `axctl` which made the backup
+    pub axctl_version: String,
+    /// When the backup was made, in seconds since the Unix epoch
+    pub created: u64,
+    pub model: Option<String>,
+    pub product_name: Option<String>,
+    pub serial_number: Option<String>,
+    pub firmware_version: Option<String>,
+    /// The files and directories backed up from the device
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl Manifest {
+    fn new(parameters: &BTreeMap<String, String>, files: Vec<String>) -> Self {
+        let get = |name: &str| parameters.get(name).filter(|v| !v.is_empty()).cloned();
+        Self {
+            axctl_version: env!("CARGO_PKG_VERSION").to_owned(),
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            model: get(MODEL),
+            product_name: get("Brand.ProdFullName"),
+            serial_number: get("Properties.System.SerialNumber"),
+            firmware_version: get(FIRMWARE_VERSION),
+            files,
+        }
+    }
+}
+
+/// Where a file or directory from the device is kept in a backup, as a gzipped tar with
+/// everything under its own name. Directories are kept in the same place with or without a
+/// trailing `/`.
+pub(super) fn entry_for(path: &str) -> String {
+    format!("{}/{}.tar.gz", FILES, path.trim_matches('/'))
+}
+
+/// A count of things, i.e. `1 file` or `2 files`.
+pub(super) fn count(n: usize, noun: &str) -> String {
+    format!("{} {}{}", n, noun, if n == 1 { "" } else { "s" })
+}
+
+#[derive(Serialize)]
+struct Fetched {
+    path: String,
+    bytes: usize,
+}
+
+impl Output for Fetched {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        crossterm::queue!(
+            stdout,
+            Clear(ClearType::CurrentLine),
+            crossterm::style::Print(format!(
+                "\r => fetched device:{} ({} bytes)\n",
+                self.path, self.bytes
+            )),
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct BackedUp {
+    destination: Option<PathBuf>,
+    parameters: usize,
+    applications: usize,
+    files: usize,
+    bytes: usize,
+}
+
+impl Output for BackedUp {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        let destination = match &self.destination {
+            Some(path) => path.display().to_string(),
+            None => "stdout".to_owned(),
+        };
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Green),
+            Print(format!(
+                " => backed up {}, {} and {} to {} ({} bytes)\n",
+                count(self.parameters, "parameter"),
+                count(self.applications, "application"),
+                count(self.files, "file"),
+                destination,
+                self.bytes
+            )),
+            ResetColor,
+        )
+    }
+}
+
+impl Backup {
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        if self.output.is_none() {
+            if context.is_tty {
+                return Err(Error::StdoutIsTerminal);
+            }
+            // stdout belongs to the backup, so don't clutter it with progress messages
+            context.global_options.verbose = false;
+            context.global_options.quiet = true;
+        }
+
+        let client = context.client();
+        let parameters = param::list(&client, &[] as &[&str]).await?;
+
+        // List applications before starting a shell, which is an application itself
+        let applications = client.applications().await?;
+        let installed = match &applications {
+            Some(applications) => applications.list().await?,
+            None => Vec::new(),
+        };
+
+        let mut fetched: Vec<(String, Vec<u8>)> = Vec::new();
+        if !self.no_files && (!installed.is_empty() || !self.include.is_empty()) {
+            let applications = applications
+                .as_ref()
+                .ok_or(shell::Error::DeviceDoesNotSupportApplicationUploads)?;
+            let Session { id, mut conn, .. } =
+                start_session(context, applications, self.port, false).await?;
+
+            let result = async {
+                // Applications keep their settings and state in `localdata`, if they keep any
+                let candidates: Vec<String> = installed
+                    .iter()
+                    .map(|a| quote(&format!("{}/{}/localdata", PACKAGES, a.name)))
+                    .collect();
+                let existing = if candidates.is_empty() {
+                    String::new()
+                } else {
+                    cp::remote(
+                        &mut conn,
+                        &format!(
+                            "for p in {}; do [ -e \"$p\" ] && echo \"$p\"; done; true",
+                            candidates.join(" ")
+                        ),
+                    )
+                    .await?
+                };
+
+                let paths = existing
+                    .lines()
+                    .map(str::to_owned)
+                    .chain(self.include.iter().cloned());
+                for path in paths {
+                    let (archive, _) = cp::fetch(context, &mut conn, &path).await?;
+                    context.output(Fetched {
+                        path: path.clone(),
+                        bytes: archive.len(),
+                    })?;
+                    fetched.push((path, archive));
+                }
+                Ok::<_, Error>(())
+            }
+            .await;
+            std::mem::drop(conn);
+
+            end_session(context, applications, id).await?;
+            result?;
+        }
+
+        let manifest = Manifest::new(
+            &parameters,
+            fetched.iter().map(|(path, _)| path.clone()).collect(),
+        );
+        let mut files = vec![
+            crate::tar::file(
+                MANIFEST,
+                serde_json::to_vec_pretty(&manifest).expect("manifest serializes"),
+            ),
+            crate::tar::file(
+                PARAMETERS,
+                serde_json::to_vec_pretty(&parameters).expect("parameters serialize"),
+            ),
+            crate::tar::file(
+                APPLICATIONS,
+                serde_json::to_vec_pretty(&installed).expect("applications serialize"),
+            ),
+        ];
+        files.extend(
+            fetched
+                .iter()
+                .map(|(path, archive)| crate::tar::file(entry_for(path), archive)),
+        );
+        let bytes = crate::tar::build(&files);
+
+        match &self.output {
+            Some(path) => {
+                std::fs::write(path, &bytes).map_err(|e| Error::WriteError(path.clone(), e))?
+            }
+            None => {
+                let mut stdout = std::io::stdout();
+                stdout
+                    .write_all(&bytes)
+                    .and_then(|_| stdout.flush())
+                    .map_err(Error::OutputError)?;
+            }
+        }
+
+        context.output(BackedUp {
+            destination: self.output,
+            parameters: parameters.len(),
+            applications: installed.len(),
+            files: fetched.len(),
+            bytes: bytes.len(),
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        assert_eq!(
+            entry_for("/etc/hosts"),
+            format!("{}/etc/hosts.tar.gz", FILES)
+        );
+        assert_eq!(
+            entry_for("usr/local/packages/vmd/"),
+            format!("{}/usr/local/packages/vmd.tar.gz", FILES)
+        );
+        assert_eq!(
+            entry_for("/usr/local/packages/vmd/localdata"),
+            format!("{}/usr/local/packages/vmd/localdata.tar.gz", FILES)
+        );
+    }
+}
@@ -0,0 +1,650 @@
+use super::exec::{self, quote, run, Completed};
+use super::shell::{self, end_session, start_session, Connection, Session};
+use crate::cli::Context;
+use crate::output::{Level, Output};
+use clap::Clap;
+use crossterm::terminal::{Clear, ClearType};
+use crossterm::{queue, style::Print};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+use tokio::prelude::*;
+use uuid::Uuid;
+
+/// Copy files and directories to or from an AXIS camera
+#[derive(Debug, Clone, Clap)]
+pub struct Cp {
+    /// The remote port on which to establish a shell-over-SSL connection
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// The file or directory to copy, prefixed with `device:` if it's on the device
+    source: String,
+
+    /// Where to copy it, prefixed with `device:` if it's on the device
+    destination: String,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    ShellError(#[from] shell::Error),
+    #[error("{0}")]
+    ExecError(#[from] exec::Error),
+    #[error("error writing to terminal: {0}")]
+    TerminalError(#[from] crossterm::ErrorKind),
+    #[error("one of the source and destination must be on the device, i.e. `device:/path`")]
+    InvalidLocations,
+    #[error("can't copy {0:?}: name a file or directory")]
+    UnsupportedPath(String),
+    #[error("error reading {0:?}: {1}")]
+    ReadError(PathBuf, std::io::Error),
+    #[error("error writing {0:?}: {1}")]
+    WriteError(PathBuf, std::io::Error),
+    #[error("error on device: {0}")]
+    RemoteError(String),
+    #[error("device reported an unrecognized checksum: {0:?}")]
+    MalformedChecksum(String),
+    #[error("checksum mismatch: device has {0}, but we have {1}")]
+    ChecksumMismatch(String, String),
+    #[error("archive contains an unexpected path {0:?}")]
+    UnexpectedPath(PathBuf),
+    #[error("archive contains {0:?}, which is neither a file nor a directory")]
+    UnsupportedEntry(PathBuf),
+}
+
+enum Location {
+    Local(PathBuf),
+    Device(String),
+}
+
+impl From<&str> for Location {
+    fn from(s: &str) -> Self {
+        match s.strip_prefix("device:") {
+            Some(path) => Location::Device(path.to_owned()),
+            None => Location::Local(s.into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Progress<'a> {
+    path: &'a str,
+    bytes: u64,
+    total: u64,
+    percent: u64,
+}
+
+impl<'a> Output for Progress<'a> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        queue!(
+            stdout,
+            Clear(ClearType::CurrentLine),
+            Print(format!(
+                "\r => {}: {}% ({} of {} bytes)",
+                &self.path, self.percent, self.bytes, self.total
+            ))
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Copied {
+    source: String,
+    destination: String,
+    bytes: u64,
+    checksum: String,
+}
+
+impl Output for Copied {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        queue!(
+            stdout,
+            Clear(ClearType::CurrentLine),
+            Print(format!(
+                "\r => copied {} to {} ({} bytes, {})\n",
+                &self.source, &self.destination, self.bytes, &self.checksum
+            ))
+        )?;
+        Ok(())
+    }
+
+    fn level(&self) -> Level {
+        Level::Info
+    }
+}
+
+/// Reports progress as a transfer advances, once per percentage point.
+struct Tracker {
+    path: String,
+    total: u64,
+    bytes: u64,
+    percent: Option<u64>,
+}
+
+impl Tracker {
+    fn new(path: String, total: u64) -> Self {
+        Self {
+            path,
+            total,
+            bytes: 0,
+            percent: None,
+        }
+    }
+
+    fn advance(&mut self, context: &mut Context, bytes: usize) -> Result<(), crossterm::ErrorKind> {
+        self.bytes += bytes as u64;
+
+        let percent = match self.total {
+            0 => 100,
+            total => self.bytes * 100 / total,
+        };
+        if self.percent != Some(percent) {
+            self.percent = Some(percent);
+            context.output(Progress {
+                path: &self.path,
+                bytes: self.bytes,
+                total: self.total,
+                percent,
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Print the checksum of `$archive` as `<algorithm> <hex>`, preferring SHA-256 where available.
+const CHECKSUM: &str = r#"if command -v sha256sum >/dev/null
+then
+  set -- `sha256sum <"$archive"` && echo sha256 $1
+else
+  set -- `md5sum <"$archive"` && echo md5 $1
+fi"#;
+
+/// Check the `<algorithm> <hex>` reported by `CHECKSUM` against `bytes`.
+fn verify(reported: &str, bytes: &[u8]) -> Result<String, Error> {
+    use openssl::hash::{hash, MessageDigest};
+
+    let mut parts = reported.split_whitespace();
+    let (algorithm, expected) = match (parts.next(), parts.next()) {
+        (Some(algorithm), Some(expected)) => (algorithm, expected.to_ascii_lowercase()),
+        _ => return Err(Error::MalformedChecksum(reported.into())),
+    };
+    let digest = match algorithm {
+        "sha256" => MessageDigest::sha256(),
+        "md5" => MessageDigest::md5(),
+        _ => return Err(Error::MalformedChecksum(reported.into())),
+    };
+
+    let actual = hash(digest, bytes)
+        .expect("error computing checksum")
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    if actual == expected {
+        Ok(format!("{}:{}", algorithm, actual))
+    } else {
+        Err(Error::ChecksumMismatch(
+            format!("{}:{}", algorithm, expected),
+            format!("{}:{}", algorithm, actual),
+        ))
+    }
+}
+
+/// Encode `bytes` for a `printf` format, passing through characters that need no escaping.
+fn printf_escape(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 4);
+    for b in bytes {
+        if b.is_ascii_alphanumeric() || b"-_.,:/+= ".contains(b) {
+            s.push(*b as char);
+        } else {
+            write!(s, "\\{:03o}", b).unwrap();
+        }
+    }
+    s
+}
+
+/// Run `command` on the device, passing its stdout to `stdout` and failing if it fails.
+async fn remote_with_stdout(
+    conn: &mut Connection,
+    command: &str,
+    stdout: &mut dyn FnMut(&[u8]) -> std::io::Result<()>,
+) -> Result<(), Error> {
+    let Completed { status, stderr } = run(conn, command, stdout).await?;
+    if status == 0 {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&stderr).trim().to_owned();
+        Err(Error::RemoteError(if stderr.is_empty() {
+            format!("command exited with status {}", status)
+        } else {
+            stderr
+        }))
+    }
+}
+
+/// Run `command` on the device, returning its stdout and failing if it fails.
+pub(super) async fn remote(conn: &mut Connection, command: &str) -> Result<String, Error> {
+    let mut stdout = Vec::new();
+    remote_with_stdout(conn, command, &mut |bytes| {
+        stdout.extend_from_slice(bytes);
+        Ok(())
+    })
+    .await?;
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// A scratch directory on the device.
+fn workdir() -> String {
+    format!("/tmp/axctl-cp.{}", Uuid::new_v4())
+}
+
+/// Send a gzipped tar to a scratch directory on the device as `archive.tar.gz`, checking that it
+/// arrived intact, and return the directory and the checksum.
+///
+/// The caller is responsible for removing the directory.
+pub(super) async fn send(
+    context: &mut Context,
+    conn: &mut Connection,
+    archive: &[u8],
+    label: String,
+) -> Result<(String, String), Error> {
+    let workdir = workdir();
+    let archive_path = format!("{}/archive.tar.gz", &workdir);
+    remote(
+        conn,
+        &format!(
+            "mkdir -p {} && : >{}",
+            quote(&workdir),
+            quote(&archive_path)
+        ),
+    )
+    .await?;
+
+    // Send the archive as a series of `printf`s, which the shell runs as they arrive
+    let mut tracker = Tracker::new(label, archive.len() as u64);
+    for chunk in archive.chunks(1024) {
+        let line = format!(
+            "printf '{}' >>{}\n",
+            printf_escape(chunk),
+            quote(&archive_path)
+        );
+        conn.write_all(line.as_bytes())
+            .await
+            .map_err(exec::Error::ConnectionClosed)?;
+        tracker.advance(context, chunk.len())?;
+    }
+    conn.flush().await.map_err(exec::Error::ConnectionClosed)?;
+
+    // Make sure it arrived intact before putting it in place
+    let reported = remote(
+        conn,
+        &format!("archive={}\n{}", quote(&archive_path), CHECKSUM),
+    )
+    .await?;
+    let checksum = match verify(&reported, archive) {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            remote(conn, &format!("rm -rf {}", quote(&workdir))).await?;
+            return Err(e);
+        }
+    };
+
+    Ok((workdir, checksum))
+}
+
+async fn upload(
+    context: &mut Context,
+    conn: &mut Connection,
+    local: &Path,
+    destination: &str,
+) -> Result<Copied, Error> {
+    // Archive the source under its own name
+    let name = std::fs::canonicalize(local)
+        .map_err(|e| Error::ReadError(local.into(), e))?
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_owned)
+        .ok_or_else(|| Error::UnsupportedPath(local.display().to_string()))?;
+    let archive =
+        crate::tar::archive_path(&name, local).map_err(|e| Error::ReadError(local.into(), e))?;
+
+    let (workdir, checksum) = send(context, conn, &archive, local.display().to_string()).await?;
+
+    // Extract into the workdir and move it into place, like `cp -R` would
+    remote(
+        conn,
+        &format!(
+            r#"workdir={} name={} dest={}
+mkdir "$workdir/stage" &&
+  tar -xzf "$workdir/archive.tar.gz" -C "$workdir/stage" &&
+  {{ [ -d "$dest" ] && dest="$dest/$name"; mv -f "$workdir/stage/$name" "$dest"; }}
+status=$?
+rm -rf "$workdir"
+exit $status"#,
+            quote(&workdir),
+            quote(&name),
+            quote(destination),
+        ),
+    )
+    .await?;
+
+    Ok(Copied {
+        source: local.display().to_string(),
+        destination: format!("device:{}", destination),
+        bytes: archive.len() as u64,
+        checksum,
+    })
+}
+
+/// Archive a file or directory on the device as a gzipped tar, with everything under its own name,
+/// and fetch the archive, returning it and its checksum.
+pub(super) async fn fetch(
+    context: &mut Context,
+    conn: &mut Connection,
+    source: &str,
+) -> Result<(Vec<u8>, String), Error> {
+    // Archive the source, then report the archive's checksum and size
+    let workdir = workdir();
+    let reported = remote(
+        conn,
+        &format!(
+            r#"src={} workdir={}
+archive="$workdir/archive.tar.gz"
+mkdir -p "$workdir" &&
+  tar -czf "$archive" -C "`dirname "$src"`" "`basename "$src"`" &&
+  {{
+{}
+  }} &&
+  wc -c <"$archive"
+status=$?
+[ $status = 0 ] || rm -rf "$workdir"
+exit $status"#,
+            quote(source),
+            quote(&workdir),
+            CHECKSUM,
+        ),
+    )
+    .await?;
+    let mut lines = reported.lines();
+    let checksum = lines.next().unwrap_or("");
+    let total = lines
+        .next()
+        .and_then(|size| size.trim().parse().ok())
+        .ok_or_else(|| Error::RemoteError(format!("unexpected output: {:?}", reported)))?;
+
+    // Fetch it
+    let mut archive = Vec::new();
+    let mut tracker = Tracker::new(format!("device:{}", source), total);
+    remote_with_stdout(
+        conn,
+        &format!(
+            r#"workdir={}
+cat "$workdir/archive.tar.gz"
+status=$?
+rm -rf "$workdir"
+exit $status"#,
+            quote(&workdir)
+        ),
+        &mut |bytes| {
+            archive.extend_from_slice(bytes);
+            tracker
+                .advance(context, bytes.len())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        },
+    )
+    .await?;
+
+    let checksum = verify(checksum, &archive)?;
+
+    Ok((archive, checksum))
+}
+
+async fn download(
+    context: &mut Context,
+    conn: &mut Connection,
+    source: &str,
+    local: &Path,
+) -> Result<Copied, Error> {
+    let name = source
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+        .ok_or_else(|| Error::UnsupportedPath(format!("device:{}", source)))?
+        .to_owned();
+
+    let (archive, checksum) = fetch(context, conn, source).await?;
+
+    let target = unpack(&archive, &name, local)?;
+
+    Ok(Copied {
+        source: format!("device:{}", source),
+        destination: target.display().to_string(),
+        bytes: archive.len() as u64,
+        checksum,
+    })
+}
+
+/// Unpack an archive whose contents are all under `name` to `local`, or into `local` if it's a
+/// directory, returning the path of what was unpacked.
+fn unpack(archive: &[u8], name: &str, local: &Path) -> Result<PathBuf, Error> {
+    let target = if local.is_dir() {
+        local.join(name)
+    } else {
+        local.to_owned()
+    };
+
+    let gz = flate2::read::GzDecoder::new(archive);
+    let mut archive = ::tar::Archive::new(gz);
+    let entries = archive
+        .entries()
+        .map_err(|e| Error::WriteError(target.clone(), e))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| Error::WriteError(target.clone(), e))?;
+        let path = entry
+            .path()
+            .map_err(|e| Error::WriteError(target.clone(), e))?
+            .into_owned();
+
+        // Everything is under `name`, which becomes `target`, and nothing may escape it
+        let relative = path
+            .strip_prefix(name)
+            .map_err(|_| Error::UnexpectedPath(path.clone()))?;
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(Error::UnexpectedPath(path.clone()));
+        }
+
+        // Links could point anywhere, and a later entry could then write through them
+        let entry_type = entry.header().entry_type();
+        if !entry_type.is_file() && !entry_type.is_dir() {
+            return Err(Error::UnsupportedEntry(path));
+        }
+
+        // A single file is `name` itself, and joining an empty path would add a trailing slash
+        let dest = if relative.as_os_str().is_empty() {
+            target.clone()
+        } else {
+            target.join(relative)
+        };
+        entry
+            .unpack(&dest)
+            .map_err(|e| Error::WriteError(dest.clone(), e))?;
+    }
+
+    Ok(target)
+}
+
+impl Cp {
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        let source = Location::from(self.source.as_str());
+        let destination = Location::from(self.destination.as_str());
+        match (&source, &destination) {
+            (Location::Local(_), Location::Device(_))
+            | (Location::Device(_), Location::Local(_)) => {}
+            _ => return Err(Error::InvalidLocations),
+        }
+
+        let client = context.client();
+        let applications = client
+            .applications()
+            .await
+            .map_err(shell::Error::VapixError)?
+            .ok_or(shell::Error::DeviceDoesNotSupportApplicationUploads)?;
+
+        let Session { id, mut conn, .. } =
+            start_session(context, &applications, self.port, false).await?;
+
+        let result = match (&source, &destination) {
+            (Location::Local(local), Location::Device(remote)) => {
+                upload(context, &mut conn, local, remote).await
+            }
+            (Location::Device(remote), Location::Local(local)) => {
+                download(context, &mut conn, remote, local).await
+            }
+            _ => unreachable!(),
+        };
+        std::mem::drop(conn);
+
+        if let Ok(copied) = &result {
+            context.output::<Copied, _>(copied)?;
+        }
+
+        end_session(context, &applications, id).await?;
+
+        result.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(entries: &[(&str, ::tar::EntryType, &str)]) -> Vec<u8> {
+        let gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut tar = ::tar::Builder::new(gz);
+        for (path, entry_type, contents) in entries {
+            // Set the name directly, since `set_path()` refuses the paths we want to test
+            let mut header = ::tar::Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_mode(0o644);
+            header.set_entry_type(*entry_type);
+            if entry_type.is_file() {
+                header.set_size(contents.len() as _);
+            } else {
+                header.set_size(0);
+                if !entry_type.is_dir() {
+                    header.set_link_name(contents).unwrap();
+                }
+            }
+            header.set_cksum();
+            let data: &[u8] = if entry_type.is_file() {
+                contents.as_bytes()
+            } else {
+                &[]
+            };
+            tar.append(&header, data).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn scratch() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("axctl-cp-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("local")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn unpack_files() {
+        use ::tar::EntryType;
+        let dir = scratch();
+        let bytes = archive(&[
+            ("name/", EntryType::Directory, ""),
+            ("name/a", EntryType::Regular, "hello"),
+        ]);
+
+        let target = unpack(&bytes, "name", &dir.join("local")).unwrap();
+        assert_eq!(target, dir.join("local/name"));
+        assert_eq!(std::fs::read_to_string(target.join("a")).unwrap(), "hello");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpack_single_file() {
+        use ::tar::EntryType;
+        let dir = scratch();
+        let bytes = archive(&[("messages", EntryType::Regular, "hello")]);
+
+        // Into a directory, under its own name
+        let target = unpack(&bytes, "messages", &dir.join("local")).unwrap();
+        assert_eq!(target, dir.join("local/messages"));
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "hello");
+
+        // To a file, under the name given
+        let target = unpack(&bytes, "messages", &dir.join("local/renamed")).unwrap();
+        assert_eq!(target, dir.join("local/renamed"));
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "hello");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_writing_through_symlink() {
+        use ::tar::EntryType;
+        let dir = scratch();
+        let outside = dir.join("outside");
+        let bytes = archive(&[
+            ("name/", EntryType::Directory, ""),
+            ("name/x", EntryType::Symlink, outside.to_str().unwrap()),
+            ("name/x/passwd", EntryType::Regular, "owned"),
+        ]);
+
+        let result = unpack(&bytes, "name", &dir.join("local"));
+        assert!(matches!(result, Err(Error::UnsupportedEntry(_))));
+        assert!(!outside.join("passwd").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_hardlinks() {
+        use ::tar::EntryType;
+        let dir = scratch();
+        let outside = dir.join("outside/secret");
+        std::fs::write(&outside, "secret").unwrap();
+        let bytes = archive(&[
+            ("name/", EntryType::Directory, ""),
+            ("name/y", EntryType::Link, outside.to_str().unwrap()),
+        ]);
+
+        let result = unpack(&bytes, "name", &dir.join("local"));
+        assert!(matches!(result, Err(Error::UnsupportedEntry(_))));
+        assert!(!dir.join("local/name/y").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_escaping_paths() {
+        use ::tar::EntryType;
+        let dir = scratch();
+        let bytes = archive(&[("name/../escaped", EntryType::Regular, "owned")]);
+
+        let result = unpack(&bytes, "name", &dir.join("local"));
+        assert!(matches!(result, Err(Error::UnexpectedPath(_))));
+        assert!(!dir.join("local/escaped").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
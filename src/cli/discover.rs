@@ -0,0 +1,325 @@
+use crate::cli::Context;
+use crate::discover::{self, responder::Responder, Search};
+use crate::inventory::{self, Inventory};
+use crate::output::{print_table, Output};
+use crate::profile::Profiles;
+use clap::Clap;
+use crossterm::ErrorKind;
+use serde::Serialize;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Find devices on the local network by mDNS and SSDP
+#[derive(Debug, Clone, Clap)]
+#[clap(setting = clap::AppSettings::VersionlessSubcommands)]
+pub struct Discover {
+    #[clap(subcommand)]
+    subcommand: Option<Subcommand>,
+
+    /// How long to wait for devices to answer, i.e. 3s or 500ms
+    #[clap(long, default_value = "3s", parse(try_from_str = super::log::parse_interval))]
+    wait: Duration,
+
+    /// Don't search by mDNS
+    #[clap(long, conflicts_with = "no-ssdp")]
+    no_mdns: bool,
+
+    /// Don't search by SSDP
+    #[clap(long)]
+    no_ssdp: bool,
+
+    /// Add devices which aren't in the inventory yet to the end of the inventory file
+    #[clap(long)]
+    add_to_inventory: bool,
+
+    /// Tag devices added to the inventory (may be repeated)
+    #[clap(long, number_of_values = 1, requires = "add-to-inventory")]
+    tag: Vec<String>,
+
+    /// Use the credentials from this profile for devices added to the inventory
+    #[clap(long, requires = "add-to-inventory")]
+    with_profile: Option<String>,
+
+    /// Send mDNS queries here rather than to the multicast group
+    #[clap(long, hidden = true, default_value = discover::MDNS_ADDRESS)]
+    mdns_address: SocketAddr,
+
+    /// Send SSDP searches here rather than to the multicast group
+    #[clap(long, hidden = true, default_value = discover::SSDP_ADDRESS)]
+    ssdp_address: SocketAddr,
+}
+
+#[derive(Debug, Clone, Clap)]
+enum Subcommand {
+    /// Answer searches as a stand-in device, for testing discovery without a device
+    #[clap(setting = clap::AppSettings::Hidden)]
+    Respond {
+        /// The address on which to answer searches
+        #[clap(long, default_value = "127.0.0.1:0")]
+        listen: SocketAddr,
+
+        /// The serial number to announce
+        #[clap(long, default_value = "ACCC8E000001")]
+        serial: String,
+
+        /// The model to announce
+        #[clap(long, default_value = "M3045-V")]
+        model: String,
+
+        /// The firmware version to report
+        #[clap(long, default_value = "10.5.0")]
+        firmware: String,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error writing to terminal: {0}")]
+    TerminalError(#[from] crossterm::ErrorKind),
+    #[error("{0}")]
+    DiscoverError(#[from] discover::Error),
+    #[error("{0}")]
+    InventoryError(#[from] inventory::Error),
+    #[error("{0}")]
+    ProfileError(#[from] crate::profile::Error),
+}
+
+impl Output for discover::Device {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        DeviceList(vec![self.clone()]).print(stdout)
+    }
+}
+
+/// A table of devices which answered.
+#[derive(Serialize)]
+struct DeviceList(Vec<discover::Device>);
+
+impl Output for DeviceList {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::style::Color;
+
+        let rows: Vec<[String; 5]> = self
+            .0
+            .iter()
+            .map(|d| {
+                [
+                    d.serial.clone().unwrap_or_else(|| "unknown".into()),
+                    d.model.clone().unwrap_or_else(|| "unknown".into()),
+                    d.url(),
+                    d.firmware.clone().unwrap_or_else(|| "unknown".into()),
+                    d.found_by.join(", "),
+                ]
+            })
+            .collect();
+
+        print_table(
+            stdout,
+            &["SERIAL", "MODEL", "URL", "FIRMWARE", "FOUND BY"],
+            &rows,
+            |row, column| {
+                let device = &self.0[row];
+                let known = match column {
+                    0 => device.serial.is_some(),
+                    1 => device.model.is_some(),
+                    3 => device.firmware.is_some(),
+                    _ => true,
+                };
+                match column {
+                    _ if !known => Color::DarkGrey,
+                    0 => Color::Cyan,
+                    _ => Color::Reset,
+                }
+            },
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct NoneFound {
+    wait: f64,
+}
+
+impl Output for NoneFound {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Yellow),
+            Print(format!(
+                " => no devices answered within {:.1}s\n",
+                self.wait
+            )),
+            ResetColor,
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct Added {
+    inventory: PathBuf,
+    devices: Vec<String>,
+    /// The profile whose credentials the devices use, without which they have none
+    profile: Option<String>,
+}
+
+impl Output for Added {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            Print(format!(
+                " => added {} device{} to {}",
+                self.devices.len(),
+                if self.devices.len() == 1 { "" } else { "s" },
+                self.inventory.display()
+            )),
+        )?;
+        for (i, name) in self.devices.iter().enumerate() {
+            queue!(
+                stdout,
+                Print(if i == 0 { ": " } else { ", " }),
+                SetAttribute(Attribute::Bold),
+                Print(name),
+                SetAttribute(Attribute::NormalIntensity),
+            )?;
+        }
+        queue!(stdout, Print("\n"))?;
+
+        if self.profile.is_none() && !self.devices.is_empty() {
+            queue!(
+                stdout,
+                SetForegroundColor(Color::Yellow),
+                Print(
+                    " => they have no credentials; give each a `profile`, or use --with-profile\n"
+                ),
+                ResetColor,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Listening {
+    udp: SocketAddr,
+    http: SocketAddr,
+}
+
+impl Output for Listening {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            Print(format!(
+                " => answering searches on {}, with a web interface on {}\n",
+                self.udp, self.http
+            )),
+        )
+    }
+}
+
+/// The name under which a device is added to the inventory.
+fn inventory_name(device: &discover::Device) -> String {
+    match &device.serial {
+        Some(serial) => format!("axis-{}", serial.to_ascii_lowercase()),
+        None => format!("axis-{}", device.ip).replace(&['.', ':'][..], "-"),
+    }
+}
+
+impl Discover {
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        if let Some(Subcommand::Respond {
+            listen,
+            serial,
+            model,
+            firmware,
+        }) = self.subcommand
+        {
+            let responder = Responder {
+                serial,
+                model,
+                firmware,
+            }
+            .bind(listen)
+            .await?;
+            context.output(Listening {
+                udp: responder.udp_address(),
+                http: responder.http_address(),
+            })?;
+            return Ok(responder.run().await?);
+        }
+
+        let devices = Search {
+            mdns: Some(self.mdns_address).filter(|_| !self.no_mdns),
+            ssdp: Some(self.ssdp_address).filter(|_| !self.no_ssdp),
+            wait: self.wait,
+        }
+        .run()
+        .await?;
+
+        if devices.is_empty() {
+            if context.is_tty {
+                context.output(NoneFound {
+                    wait: self.wait.as_secs_f64(),
+                })?;
+            }
+        } else if context.is_tty {
+            context.output(DeviceList(devices.clone()))?;
+        } else {
+            for device in devices.iter().cloned() {
+                context.output(device)?;
+            }
+        }
+
+        if self.add_to_inventory {
+            if let Some(name) = &self.with_profile {
+                Profiles::load(&Profiles::default_path()?)?.get(name)?;
+            }
+
+            let path = context.global_options.inventory.clone();
+            let inventory = if path.exists() {
+                Inventory::load(&path)?
+            } else {
+                Inventory::default()
+            };
+
+            let added: Vec<(String, inventory::Device)> = devices
+                .iter()
+                .filter(|device| {
+                    let url = device.url();
+                    let host = url.parse::<http::Uri>().ok();
+                    let host = host.as_ref().and_then(|uri| uri.host());
+                    !matches!(host, Some(host) if inventory.find_host(host).is_some())
+                })
+                .map(|device| {
+                    (
+                        inventory_name(device),
+                        inventory::Device {
+                            url: device.url(),
+                            profile: self.with_profile.clone(),
+                            tags: self.tag.clone(),
+                        },
+                    )
+                })
+                .filter(|(name, _)| !inventory.contains(name))
+                .collect();
+
+            if !added.is_empty() {
+                Inventory::append(&path, &added)?;
+            }
+            context.output(Added {
+                inventory: path,
+                devices: added.into_iter().map(|(name, _)| name).collect(),
+                profile: self.with_profile,
+            })?;
+        }
+
+        Ok(())
+    }
+}
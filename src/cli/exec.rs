@@ -0,0 +1,208 @@
+use super::shell::{self, end_session, start_session, Connection, Session};
+use crate::cli::Context;
+use clap::Clap;
+use std::io::Write;
+use thiserror::Error;
+use tokio::prelude::*;
+use uuid::Uuid;
+
+/// Run a single command on an AXIS camera
+#[derive(Debug, Clone, Clap)]
+pub struct Exec {
+    /// The remote port on which to establish a shell-over-SSL connection
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// The command to run, which is interpreted by the device's shell
+    #[clap(required = true, last = true)]
+    command: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    ShellError(#[from] shell::Error),
+    #[error("error writing to stdout: {0}")]
+    OutputError(std::io::Error),
+    #[error("connection closed: {0}")]
+    ConnectionClosed(std::io::Error),
+    #[error("connection closed before the command completed")]
+    UnexpectedEof,
+    #[error("unable to determine the command's exit status")]
+    MalformedTrailer,
+}
+
+/// Quote `s` for inclusion in a shell command line.
+pub(super) fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// A script which runs `command` in the remote shell, leaving the shell ready for the next one.
+///
+/// The command's stdout is bracketed by markers so we can distinguish it from anything the shell
+/// prints before or after. Its stderr is collected into a file and sent after the end marker,
+/// along with the exit status. The markers are assembled by `printf` so that the script itself
+/// never contains them, even if the shell echoes its input.
+fn script(id: Uuid, command: &str) -> String {
+    format!(
+        r#"PS1= PS2=
+stderr=/tmp/axctl-exec.{id}.stderr
+printf '%s%s\n' 'axctl-begin-' '{id}'
+sh -c {command} </dev/null 2>"$stderr"
+status=$?
+printf '\n%s%s %d %d\n' 'axctl-end-' '{id}' $status `wc -c <"$stderr"`
+cat "$stderr"
+rm -f "$stderr"
+"#,
+        id = id,
+        command = quote(command),
+    )
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+enum Phase {
+    /// Discarding anything the shell prints before the begin marker
+    Preamble,
+    /// Passing through the command's stdout, up to the end marker
+    Stdout,
+    /// Reading the exit status and the length of stderr
+    Trailer,
+    /// Reading stderr
+    Stderr { status: i32, len: usize },
+}
+
+/// A command which ran to completion.
+pub(super) struct Completed {
+    pub status: i32,
+    pub stderr: Vec<u8>,
+}
+
+/// Run `command` over `conn`, passing its stdout to `stdout` as it arrives.
+pub(super) async fn run(
+    conn: &mut Connection,
+    command: &str,
+    stdout: &mut dyn FnMut(&[u8]) -> std::io::Result<()>,
+) -> Result<Completed, Error> {
+    let id = Uuid::new_v4();
+
+    conn.write_all(script(id, command).as_bytes())
+        .await
+        .map_err(Error::ConnectionClosed)?;
+    conn.flush().await.map_err(Error::ConnectionClosed)?;
+
+    let begin = format!("axctl-begin-{}\n", id).into_bytes();
+    let end = format!("\naxctl-end-{} ", id).into_bytes();
+
+    let mut phase = Phase::Preamble;
+    let mut pending: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        // Process as much of what we have as possible
+        loop {
+            match phase {
+                Phase::Preamble => match find(&pending, &begin) {
+                    Some(i) => {
+                        pending.drain(..i + begin.len());
+                        phase = Phase::Stdout;
+                    }
+                    None => break,
+                },
+                Phase::Stdout => {
+                    // Write everything up to the end marker, or everything which can't be the
+                    // start of an end marker
+                    let (writable, found) = match find(&pending, &end) {
+                        Some(i) => (i, true),
+                        None => (pending.len().saturating_sub(end.len() - 1), false),
+                    };
+                    if writable > 0 {
+                        stdout(&pending[..writable]).map_err(Error::OutputError)?;
+                    }
+
+                    if found {
+                        pending.drain(..writable + end.len());
+                        phase = Phase::Trailer;
+                    } else {
+                        pending.drain(..writable);
+                        break;
+                    }
+                }
+                Phase::Trailer => match pending.iter().position(|b| *b == b'\n') {
+                    Some(i) => {
+                        let trailer = String::from_utf8_lossy(&pending[..i]).into_owned();
+                        let mut parts = trailer.split_whitespace();
+                        let status = parts.next().and_then(|s| s.parse().ok());
+                        let len = parts.next().and_then(|s| s.parse().ok());
+                        match (status, len) {
+                            (Some(status), Some(len)) => {
+                                pending.drain(..=i);
+                                phase = Phase::Stderr { status, len };
+                            }
+                            _ => return Err(Error::MalformedTrailer),
+                        }
+                    }
+                    None => break,
+                },
+                Phase::Stderr { status, len } => {
+                    if pending.len() < len {
+                        break;
+                    }
+                    pending.truncate(len);
+                    return Ok(Completed {
+                        status,
+                        stderr: pending,
+                    });
+                }
+            }
+        }
+
+        match conn.read(&mut buf).await {
+            Ok(0) => return Err(Error::UnexpectedEof),
+            Ok(n) => pending.extend_from_slice(&buf[..n]),
+            Err(e) => return Err(Error::ConnectionClosed(e)),
+        }
+    }
+}
+
+impl Exec {
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        // stdout belongs to the remote command, so don't clutter it with progress messages
+        context.global_options.verbose = false;
+        context.global_options.quiet = true;
+
+        let client = context.client();
+        let applications = client
+            .applications()
+            .await
+            .map_err(shell::Error::VapixError)?
+            .ok_or(shell::Error::DeviceDoesNotSupportApplicationUploads)?;
+
+        let Session { id, mut conn, .. } =
+            start_session(context, &applications, self.port, false).await?;
+
+        let result = run(&mut conn, &self.command.join(" "), &mut |bytes| {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(bytes)?;
+            stdout.flush()
+        })
+        .await;
+        std::mem::drop(conn);
+
+        end_session(context, &applications, id).await?;
+
+        let Completed { status, stderr } = result?;
+        let mut our_stderr = std::io::stderr();
+        our_stderr
+            .write_all(&stderr)
+            .and_then(|_| our_stderr.flush())
+            .map_err(Error::OutputError)?;
+
+        context.exit_code = status;
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use crate::cli::Context;
+use crate::output::Output;
+use clap::Clap;
+use crossterm::ErrorKind;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use thiserror::Error;
+
+/// Display information about the device
+#[derive(Debug, Clone, Clap)]
+pub struct Info {}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error writing to terminal: {0}")]
+    TerminalError(#[from] crossterm::ErrorKind),
+    #[error("VAPIX call failed: {0}")]
+    VapixCallFailed(#[from] vapix::Error),
+}
+
+/// What the device says about itself, where every field might be missing on older firmware.
+#[derive(Debug, Default, Serialize)]
+struct DeviceInfo {
+    product_name: Option<String>,
+    model: Option<String>,
+    serial_number: Option<String>,
+    hardware_id: Option<String>,
+    firmware_version: Option<String>,
+    build_date: Option<String>,
+    architecture: Option<String>,
+    soc: Option<String>,
+    /// Seconds since the device started
+    uptime: Option<u64>,
+}
+
+impl DeviceInfo {
+    fn is_empty(&self) -> bool {
+        self.product_name.is_none()
+            && self.model.is_none()
+            && self.serial_number.is_none()
+            && self.firmware_version.is_none()
+    }
+
+    /// Fill in fields from the basic device info API, which firmware 8.50 and later provide.
+    fn fill_from_properties(&mut self, p: vapix::v4::basic_device_info::Properties) {
+        let fields = vec![
+            (&mut self.product_name, p.product_full_name),
+            (&mut self.model, p.product_number),
+            (&mut self.serial_number, p.serial_number),
+            (&mut self.hardware_id, p.hardware_id),
+            (&mut self.firmware_version, p.firmware_version),
+            (&mut self.build_date, p.firmware_build_date),
+            (&mut self.architecture, p.soc_architecture),
+            (&mut self.soc, p.soc),
+        ];
+        for (field, value) in fields {
+            if field.is_none() && !value.is_empty() {
+                *field = Some(value);
+            }
+        }
+    }
+
+    /// Fill in fields from the parameters which hold the same information on any firmware.
+    fn fill_from_parameters(&mut self, mut params: BTreeMap<String, String>) {
+        let fields = vec![
+            (&mut self.product_name, "Brand.ProdFullName"),
+            (&mut self.model, "Brand.ProdNbr"),
+            (&mut self.serial_number, "Properties.System.SerialNumber"),
+            (&mut self.hardware_id, "Properties.System.HardwareID"),
+            (&mut self.firmware_version, "Properties.Firmware.Version"),
+            (&mut self.build_date, "Properties.Firmware.BuildDate"),
+            (&mut self.architecture, "Properties.System.Architecture"),
+            (&mut self.soc, "Properties.System.Soc"),
+        ];
+        for (field, name) in fields {
+            if field.is_none() {
+                *field = params.remove(name).filter(|v| !v.is_empty());
+            }
+        }
+    }
+}
+
+/// Format a number of seconds like `3d 4h 12m`.
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m {}s", minutes, seconds % 60),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+impl Output for DeviceInfo {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        let uptime = self.uptime.map(format_uptime);
+        let rows = [
+            ("Product", &self.product_name),
+            ("Model", &self.model),
+            ("Serial number", &self.serial_number),
+            ("Hardware ID", &self.hardware_id),
+            ("Firmware", &self.firmware_version),
+            ("Build date", &self.build_date),
+            ("Architecture", &self.architecture),
+            ("SOC", &self.soc),
+            ("Uptime", &uptime),
+        ];
+
+        for (heading, value) in rows.iter() {
+            queue!(
+                stdout,
+                Print(format!("{:>14}: ", heading)),
+                SetAttribute(if value.is_some() {
+                    Attribute::Bold
+                } else {
+                    Attribute::Dim
+                }),
+                Print(value.as_deref().unwrap_or("unknown")),
+                SetAttribute(Attribute::NormalIntensity),
+                Print("\n"),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Ask the system ready API how long the device has been up, which it answers without credentials.
+async fn uptime(context: &Context) -> Option<u64> {
+    let request = http::Request::post(context.uri_for("/axis-cgi/systemready.cgi").ok()?)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(
+            r#"{"apiVersion":"1.0","method":"systemready","params":{"timeout":1}}"#,
+        ))
+        .ok()?;
+
+    let response = context.http_client().request(request).await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await.ok()?;
+    let value: serde_json::Value = serde_json::from_slice(&body).ok()?;
+
+    // Documented as a string, but accept a number too
+    match &value["data"]["uptime"] {
+        serde_json::Value::String(s) => s.parse().ok(),
+        other => other.as_u64(),
+    }
+}
+
+impl Info {
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        let client = context.client();
+        let mut info = DeviceInfo::default();
+
+        // Older firmware has neither API discovery nor basic device info, which is fine
+        if let Ok(services) = client.services().await {
+            if let Some(basic_device_info) = services.basic_device_info {
+                if let Ok(properties) = basic_device_info.properties().await {
+                    info.fill_from_properties(properties);
+                }
+            }
+        }
+
+        match super::param::list(&client, &["Brand", "Properties"]).await {
+            Ok(params) => info.fill_from_parameters(params),
+            // Without either source there's nothing to show, so say why
+            Err(e) if info.is_empty() => return Err(e.into()),
+            Err(_) => {}
+        }
+
+        info.uptime = uptime(context).await;
+
+        context.output(info)?;
+        Ok(())
+    }
+}
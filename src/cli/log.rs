@@ -1,16 +1,26 @@
+use self::line::{Line, Sourced};
 use crate::cli::Context;
 use crate::output::Output;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime};
 use clap::Clap;
+use futures::stream::{self, StreamExt};
+use regex::Regex;
 use serde::Serialize;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::io::{Stdout, Write};
-use std::time::Duration;
+use std::cmp::Ordering;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use vapix::v3::system_log::{self, *};
 
+mod capture;
+mod follow;
+mod forward;
+mod line;
+mod stats;
+
 /// Print the system log
-#[derive(Debug, Clap)]
+#[derive(Debug, Clone, Clap)]
 pub struct Log {
     /// Print at most this many lines
     #[clap(short, long)]
@@ -19,6 +29,225 @@ pub struct Log {
     /// Whether to keep following
     #[clap(short, long)]
     follow: bool,
+
+    /// How often to check for new entries when following, e.g. 500ms, 2s or 1m
+    #[clap(long, default_value = "500ms", parse(try_from_str = parse_interval))]
+    interval: Duration,
+
+    /// When following, give up after this many consecutive errors instead of retrying forever
+    #[clap(long)]
+    max_failures: Option<u32>,
+
+    /// The fields to print, from timestamp, hostname, level and source
+    #[clap(long, use_delimiter = true, possible_values = &["timestamp", "hostname", "level", "source"], conflicts_with = "format")]
+    fields: Vec<Field>,
+
+    /// A template for each entry, e.g. "{timestamp} {hostname} {level} {message}"
+    #[clap(long)]
+    format: Option<Template>,
+
+    /// Forward entries to a syslog collector, e.g. udp://10.0.0.1:514 or tcp://10.0.0.1:514
+    #[clap(long)]
+    forward: Option<forward::Destination>,
+
+    /// Append the entries printed to a file, from which they can be replayed later
+    #[clap(long, parse(from_os_str), value_hint = clap::ValueHint::FilePath)]
+    save: Option<PathBuf>,
+
+    /// Read entries from a file made with --save instead of from a device
+    #[clap(long, parse(from_os_str), value_hint = clap::ValueHint::FilePath, conflicts_with = "follow")]
+    replay: Option<PathBuf>,
+
+    /// Summarize the log instead of printing it
+    #[clap(long, conflicts_with = "follow")]
+    stats: bool,
+
+    /// Fail on lines of the log which can't be parsed, rather than printing them as they are
+    #[clap(long)]
+    strict: bool,
+
+    #[clap(flatten)]
+    filter: Filter,
+}
+
+// Which entries to print, as a plain comment since a doc comment would replace the `Log` about
+#[derive(Debug, Clone, Clap)]
+struct Filter {
+    /// Print only entries at least this severe
+    #[clap(short, long, parse(try_from_str = parse_level), possible_values = &["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"])]
+    level: Option<Level>,
+
+    /// Print only entries from this process (may be repeated)
+    #[clap(short, long, number_of_values = 1)]
+    source: Vec<String>,
+
+    /// Print only entries whose message matches this regular expression
+    #[clap(short, long)]
+    grep: Option<Regex>,
+
+    /// Print only entries at or after this time, e.g. 2020-10-10T12:30:00-05:00 or 15m
+    #[clap(long, parse(try_from_str = parse_time))]
+    since: Option<Time>,
+
+    /// Print only entries at or before this time, e.g. 2020-10-10T12:30:00-05:00 or 15m
+    #[clap(long, parse(try_from_str = parse_time))]
+    until: Option<Time>,
+}
+
+impl Filter {
+    fn matches(&self, line: &Line) -> bool {
+        let entry = match line {
+            Line::Entry(entry) => entry,
+            // Only the message of an unparsed line is known, so it fails any other filter
+            Line::Unparsed(text) => {
+                let filtered = self.level.is_some()
+                    || !self.source.is_empty()
+                    || self.since.is_some()
+                    || self.until.is_some();
+                return !filtered && self.grep.iter().all(|grep| grep.is_match(text));
+            }
+        };
+
+        if let Some(level) = self.level {
+            if entry.level > level {
+                return false;
+            }
+        }
+
+        if !self.source.is_empty() {
+            let name = match entry.source {
+                Source::None => return false,
+                Source::Name(name) | Source::NameAndPid(name, _) => name,
+            };
+            if !self.source.iter().any(|source| source == name) {
+                return false;
+            }
+        }
+
+        if let Some(grep) = &self.grep {
+            if !grep.is_match(entry.message) {
+                return false;
+            }
+        }
+
+        if let Some(since) = &self.since {
+            if since.cmp_timestamp(&entry.timestamp) == Ordering::Greater {
+                return false;
+            }
+        }
+
+        if let Some(until) = &self.until {
+            if until.cmp_timestamp(&entry.timestamp) == Ordering::Less {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+fn parse_level(s: &str) -> Result<Level, String> {
+    Ok(match s.to_ascii_lowercase().as_str() {
+        "emerg" | "emergency" => Level::Emergency,
+        "alert" => Level::Alert,
+        "crit" | "critical" => Level::Critical,
+        "err" | "error" => Level::Error,
+        "warning" | "warn" => Level::Warning,
+        "notice" => Level::Notice,
+        "info" => Level::Info,
+        "debug" => Level::Debug,
+        _ => return Err(format!("unknown level {:?}", s)),
+    })
+}
+
+/// Parse a duration like `500ms`, `2s` or `1m`, or a number of seconds.
+pub(super) fn parse_interval(s: &str) -> Result<Duration, String> {
+    let (n, unit) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else {
+        (s, 1.0)
+    };
+
+    match n.parse::<f64>() {
+        Ok(n) if n > 0.0 && (n * unit).is_finite() => Ok(Duration::from_secs_f64(n * unit)),
+        _ => Err(format!("{:?} is not a duration like 500ms, 2s or 1m", s)),
+    }
+}
+
+/// A point in time given on the command line.
+#[derive(Debug, Clone)]
+enum Time {
+    /// A time with an offset, which is compared exactly
+    Absolute(DateTime<FixedOffset>),
+    /// A time without an offset, which is compared as a wall-clock time
+    Naive(NaiveDateTime),
+}
+
+impl Time {
+    /// Compare this time against a log entry's timestamp.
+    ///
+    /// Log timestamps may or may not have an offset, so when exactly one side has one, both are
+    /// compared as wall-clock times, each in its own offset. Devices don't say which offset their
+    /// timestamps without one are in, so a time with an offset, like one from `15m`, only compares
+    /// correctly with them when the device is in the same time zone.
+    fn cmp_timestamp(&self, timestamp: &Timestamp) -> Ordering {
+        match (self, timestamp) {
+            (Time::Absolute(t), Timestamp::FixedOffset(ts)) => t.cmp(ts),
+            (Time::Absolute(t), Timestamp::Naive(ts)) => t.naive_local().cmp(ts),
+            (Time::Naive(t), Timestamp::FixedOffset(ts)) => t.cmp(&ts.naive_local()),
+            (Time::Naive(t), Timestamp::Naive(ts)) => t.cmp(ts),
+        }
+    }
+}
+
+/// Parse an RFC 3339 timestamp, a local date and time, a date, or a duration ago like `90s`, `15m`,
+/// `2h` or `1d`.
+fn parse_time(s: &str) -> Result<Time, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(Time::Absolute(t));
+    }
+
+    for format in &[
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(Time::Naive(t));
+        }
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(Time::Naive(date.and_hms_opt(0, 0, 0).unwrap()));
+    }
+
+    let (n, unit) = match s.char_indices().last() {
+        Some((i, unit)) => (&s[..i], unit),
+        None => ("", ' '),
+    };
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => 0,
+    };
+    let now: DateTime<FixedOffset> = Local::now().into();
+    let ago = match n.parse::<i64>() {
+        // Durations too long to subtract are as invalid as any other
+        Ok(n) if seconds > 0 && n >= 0 => n
+            .checked_mul(seconds)
+            .and_then(|s| chrono::Duration::from_std(Duration::from_secs(s as u64)).ok())
+            .and_then(|d| now.checked_sub_signed(d)),
+        _ => None,
+    };
+    ago.map(Time::Absolute)
+        .ok_or_else(|| format!("{:?} is not a timestamp, date or duration like 15m", s))
 }
 
 #[derive(Debug, Error)]
@@ -27,6 +256,96 @@ pub enum Error {
     TerminalError(#[from] crossterm::ErrorKind),
     #[error("error communicating with camera via VAPIX: {0}")]
     VapixError(#[from] vapix::Error),
+    #[error("{0}")]
+    ForwardError(#[from] forward::Error),
+    #[error("{0}")]
+    CaptureError(#[from] capture::Error),
+    #[error("unable to parse log line {0:?}")]
+    UnparsedLine(String),
+}
+
+/// A field of a log entry.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Field {
+    Timestamp,
+    Hostname,
+    Level,
+    Source,
+    Message,
+}
+
+impl std::str::FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "timestamp" => Field::Timestamp,
+            "hostname" => Field::Hostname,
+            "level" => Field::Level,
+            "source" => Field::Source,
+            "message" => Field::Message,
+            _ => return Err(format!("unknown field {:?}", s)),
+        })
+    }
+}
+
+/// A template like `{timestamp} {level} {message}`, in which `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq)]
+struct Template(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    literal.push(c);
+                }
+                ('{', _) => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err("unmatched `{` in template".into()),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field(name.parse()?));
+                }
+                ('}', _) => return Err("unmatched `}` in template".into()),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Template(segments))
+    }
+}
+
+impl Template {
+    fn fields(&self) -> impl Iterator<Item = Field> + '_ {
+        self.0.iter().filter_map(|segment| match segment {
+            Segment::Field(field) => Some(*field),
+            Segment::Literal(_) => None,
+        })
+    }
 }
 
 struct Fields {
@@ -36,8 +355,34 @@ struct Fields {
     source: bool,
 }
 
+impl Default for Fields {
+    fn default() -> Self {
+        Fields {
+            timestamp: true,
+            hostname: false,
+            level: true,
+            source: true,
+        }
+    }
+}
+
+impl<I: IntoIterator<Item = Field>> From<I> for Fields {
+    fn from(fields: I) -> Self {
+        let fields: Vec<Field> = fields.into_iter().collect();
+        Fields {
+            timestamp: fields.contains(&Field::Timestamp),
+            hostname: fields.contains(&Field::Hostname),
+            level: fields.contains(&Field::Level),
+            source: fields.contains(&Field::Source),
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct Entry<'a> {
+    /// The device the entry came from, when a capture of several devices is replayed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     timestamp: Option<Timestamp>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -47,67 +392,45 @@ struct Entry<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     source: Option<Source<'a>>,
     message: &'a str,
+    /// The entry rendered using `template`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    formatted: Option<String>,
+    /// Whether this is a line of the log which could not be parsed, and so has only a message
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    unparsed: bool,
+    #[serde(skip)]
+    template: Option<&'a Template>,
 }
 
-impl<'a> Output for Entry<'a> {
-    fn print(&self, stdout: &mut Stdout) -> Result<(), crossterm::ErrorKind> {
-        use crossterm::{queue, style::*};
-
-        if let Some(timestamp) = self.timestamp {
-            queue!(
-                stdout,
-                SetForegroundColor(Color::Grey),
-                Print(format!("{} ", timestamp)),
-            )?;
-        }
-
-        if let Some(hostname) = self.hostname {
-            queue!(
-                stdout,
-                SetForegroundColor(Color::DarkGrey),
-                Print(hostname),
-                Print(" ")
-            )?;
-        }
-
-        if let Some(level) = self.level {
-            queue!(
-                stdout,
-                SetForegroundColor(match level {
-                    Level::Emergency | Level::Alert | Level::Critical | Level::Error => Color::Red,
-                    Level::Warning => Color::Yellow,
-                    Level::Notice | Level::Info => Color::Green,
-                    Level::Debug => Color::DarkGrey,
-                    Level::Repeated => Color::White,
-                }),
-                Print(format!("{} ", level)),
-            )?;
-        }
-
-        if let Some(source) = &self.source {
-            queue!(
-                stdout,
-                SetForegroundColor(Color::Cyan),
-                Print(format!("{} ", source)),
-            )?;
-        }
-
-        queue!(stdout, ResetColor, Print(self.message), Print("\n"))
-    }
-}
+impl<'a> Entry<'a> {
+    fn new(line: Line<'a>, fields: &'_ Fields, template: Option<&'a Template>) -> Self {
+        let entry = match line {
+            Line::Entry(entry) => entry,
+            Line::Unparsed(message) => {
+                return Self::with_template(
+                    Self {
+                        device: None,
+                        timestamp: None,
+                        hostname: None,
+                        level: None,
+                        source: None,
+                        message,
+                        formatted: None,
+                        unparsed: true,
+                        template,
+                    },
+                    template,
+                )
+            }
+        };
 
-impl<'a> From<(system_log::Entry<'a>, &'_ Fields)> for Entry<'a> {
-    fn from(t: (system_log::Entry<'a>, &'_ Fields)) -> Self {
-        let (
-            system_log::Entry {
-                timestamp,
-                hostname,
-                level,
-                source,
-                message,
-            },
-            fields,
-        ) = t;
+        let system_log::Entry {
+            timestamp,
+            hostname,
+            level,
+            source,
+            message,
+        } = entry;
 
         let timestamp = if fields.timestamp {
             Some(timestamp)
@@ -126,13 +449,122 @@ impl<'a> From<(system_log::Entry<'a>, &'_ Fields)> for Entry<'a> {
             None
         };
 
-        Self {
-            timestamp,
-            hostname,
-            level,
-            source,
-            message,
+        Self::with_template(
+            Self {
+                device: None,
+                timestamp,
+                hostname,
+                level,
+                source,
+                message,
+                formatted: None,
+                unparsed: false,
+                template,
+            },
+            template,
+        )
+    }
+
+    fn with_template(mut entry: Self, template: Option<&'a Template>) -> Self {
+        entry.formatted = template.map(|template| {
+            template
+                .0
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Literal(s) => s.clone(),
+                    Segment::Field(field) => entry.field(*field),
+                })
+                .collect()
+        });
+        entry
+    }
+
+    /// The text of a field, which is empty if the field is absent.
+    fn field(&self, field: Field) -> String {
+        match field {
+            Field::Timestamp => self.timestamp.map(|t| t.to_string()),
+            Field::Hostname => self.hostname.map(str::to_owned),
+            Field::Level => self.level.map(|l| l.to_string()),
+            Field::Source => self.source.as_ref().map(|s| s.to_string()),
+            Field::Message => Some(self.message.to_owned()),
+        }
+        .unwrap_or_default()
+    }
+
+    fn color(&self, field: Field) -> crossterm::style::Color {
+        use crossterm::style::Color;
+
+        match field {
+            Field::Timestamp => Color::Grey,
+            Field::Hostname => Color::DarkGrey,
+            Field::Level => level_color(self.level),
+            Field::Source => Color::Cyan,
+            Field::Message => Color::Reset,
+        }
+    }
+}
+
+impl<'a> Output for Entry<'a> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        if let Some(device) = self.device {
+            queue!(
+                stdout,
+                SetAttribute(Attribute::Bold),
+                Print(device),
+                Print(" "),
+                SetAttribute(Attribute::NormalIntensity),
+            )?;
+        }
+
+        if let Some(template) = self.template {
+            for segment in &template.0 {
+                match segment {
+                    Segment::Literal(s) => queue!(stdout, ResetColor, Print(s))?,
+                    Segment::Field(field) => queue!(
+                        stdout,
+                        SetForegroundColor(self.color(*field)),
+                        Print(self.field(*field))
+                    )?,
+                }
+            }
+            return queue!(stdout, ResetColor, Print("\n"));
+        }
+
+        for field in &[
+            Field::Timestamp,
+            Field::Hostname,
+            Field::Level,
+            Field::Source,
+        ] {
+            let text = self.field(*field);
+            if !text.is_empty() {
+                queue!(
+                    stdout,
+                    SetForegroundColor(self.color(*field)),
+                    Print(text),
+                    Print(" ")
+                )?;
+            }
         }
+
+        queue!(stdout, ResetColor, Print(self.message), Print("\n"))
+    }
+}
+
+fn level_color(level: Option<Level>) -> crossterm::style::Color {
+    use crossterm::style::Color;
+
+    match level {
+        Some(Level::Emergency)
+        | Some(Level::Alert)
+        | Some(Level::Critical)
+        | Some(Level::Error) => Color::Red,
+        Some(Level::Warning) => Color::Yellow,
+        Some(Level::Notice) | Some(Level::Info) => Color::Green,
+        Some(Level::Debug) => Color::DarkGrey,
+        Some(Level::Repeated) | None => Color::White,
     }
 }
 
@@ -140,7 +572,7 @@ impl<'a> From<(system_log::Entry<'a>, &'_ Fields)> for Entry<'a> {
 struct Entries<'a>(Vec<Entry<'a>>);
 
 impl<'a> Output for Entries<'a> {
-    fn print(&self, stdout: &mut Stdout) -> Result<(), crossterm::ErrorKind> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
         for entry in &self.0 {
             entry.print(stdout)?;
         }
@@ -148,69 +580,387 @@ impl<'a> Output for Entries<'a> {
     }
 }
 
-fn hash(e: &system_log::Entry) -> u64 {
-    let mut h = DefaultHasher::new();
-    e.hash(&mut h);
-    h.finish()
+/// What's printed for a batch of selected lines: the lines themselves, or their summary.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Report<'a> {
+    Entries(Entries<'a>),
+    Stats(stats::Stats),
+}
+
+impl<'a> Output for Report<'a> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        match self {
+            Report::Entries(entries) => entries.print(stdout),
+            Report::Stats(stats) => stats.print(stdout),
+        }
+    }
+}
+
+/// Where entries go besides the output.
+struct Sinks {
+    forwarder: Option<forward::Forwarder>,
+    capture: Option<capture::Capture>,
+}
+
+impl Sinks {
+    async fn send(&mut self, lines: &[Sourced<'_>]) -> Result<(), Error> {
+        if let Some(capture) = self.capture.as_mut() {
+            capture.save(lines)?;
+        }
+        if let Some(forwarder) = self.forwarder.as_mut() {
+            for sourced in lines {
+                forwarder.send(&sourced.line).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A device whose log is being followed as part of a merged stream.
+struct Device {
+    context: Context,
+    cursor: follow::Cursor,
+    backoff: follow::Backoff,
+    connected: bool,
+    number: Option<usize>,
+    retry_at: Instant,
 }
 
-impl<'a> Entries<'a> {
-    fn new(
-        entries: &'a system_log::Entries,
-        config: &'_ Fields,
-        n: Option<usize>,
-        previous: Option<u64>,
-    ) -> (Self, Option<u64>) {
-        let mut resume_at = None;
-        let mut keepers: Vec<Entry> = entries
+impl Log {
+    /// Whether this invocation keeps following the log.
+    pub fn is_following(&self) -> bool {
+        self.follow
+    }
+
+    /// Whether this invocation reads a capture rather than a device's log.
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    async fn sinks(&self) -> Result<Sinks, Error> {
+        let forwarder = match &self.forward {
+            Some(destination) => Some(forward::Forwarder::connect(destination).await?),
+            None => None,
+        };
+        let capture = match &self.save {
+            Some(path) => Some(capture::Capture::open(path)?),
+            None => None,
+        };
+        Ok(Sinks { forwarder, capture })
+    }
+
+    /// Send selected lines to the sinks, then print them or their summary.
+    async fn emit(
+        &self,
+        context: &mut Context,
+        selected: Vec<Line<'_>>,
+        sinks: &mut Sinks,
+    ) -> Result<(), Error> {
+        let device = context.target.name.clone();
+        let saved: Vec<Sourced> = selected
             .iter()
-            .filter_map(|e| e.ok())
-            .take(n.unwrap_or(usize::MAX))
-            .map(|e| (hash(&e), e))
-            .take_while(|(hash, _)| match previous {
-                Some(prev) if prev == *hash => false,
-                _ => true,
+            .map(|line| Sourced {
+                device: device.as_deref(),
+                line: line.clone(),
             })
-            .map(|(hash, e)| {
-                if resume_at.is_none() {
-                    resume_at = Some(hash);
-                }
-                Entry::from((e, config))
+            .collect();
+        sinks.send(&saved).await?;
+
+        // The context says which device the output is from
+        let selected = selected
+            .into_iter()
+            .map(|line| Sourced { device: None, line })
+            .collect();
+        if let Some(report) = self.report(selected) {
+            context.output(report)?;
+        }
+
+        Ok(())
+    }
+
+    /// What to print for selected lines, if anything.
+    fn report<'a>(&'a self, selected: Vec<Sourced<'a>>) -> Option<Report<'a>> {
+        if self.stats {
+            let lines: Vec<Line> = selected.into_iter().map(|s| s.line).collect();
+            Some(Report::Stats(stats::Stats::new(&lines)))
+        } else if !selected.is_empty() {
+            let fields = self.fields();
+            let format = self.format.as_ref();
+            Some(Report::Entries(Entries(
+                selected
+                    .into_iter()
+                    .map(|Sourced { device, line }| Entry {
+                        device,
+                        ..Entry::new(line, &fields, format)
+                    })
+                    .collect(),
+            )))
+        } else {
+            None
+        }
+    }
+
+    /// Print the entries from a capture as though they came from a device.
+    async fn replay(&self, context: &mut Context, path: &Path) -> Result<(), Error> {
+        let records = capture::load(path)?;
+        let mut sinks = self.sinks().await?;
+
+        let lines = records.iter().map(capture::Record::sourced).collect();
+        let selected = self.select(lines, &mut self.number.clone())?;
+        sinks.send(&selected).await?;
+
+        if let Some(report) = self.report(selected) {
+            context.output(report)?;
+        }
+        Ok(())
+    }
+
+    /// The fields to include in each entry.
+    fn fields(&self) -> Fields {
+        // A template determines the fields, and otherwise they can be chosen explicitly
+        match (&self.format, self.fields.is_empty()) {
+            (Some(template), _) => Fields::from(template.fields()),
+            (None, false) => Fields::from(self.fields.iter().copied()),
+            (None, true) => Fields::default(),
+        }
+    }
+
+    /// Filter new lines, then keep the last `number` if this is the first batch.
+    ///
+    /// This fails if `--strict` was given and any line could not be parsed.
+    fn select<'a, L: AsRef<Line<'a>>>(
+        &self,
+        new: Vec<L>,
+        number: &mut Option<usize>,
+    ) -> Result<Vec<L>, Error> {
+        if self.strict {
+            if let Some(Line::Unparsed(text)) = new
+                .iter()
+                .map(AsRef::as_ref)
+                .find(|line| matches!(line, Line::Unparsed(_)))
+            {
+                return Err(Error::UnparsedLine((*text).to_owned()));
+            }
+        }
+
+        let mut selected: Vec<L> = new
+            .into_iter()
+            .filter(|e| self.filter.matches(e.as_ref()))
+            .collect();
+        if let Some(number) = number.take() {
+            selected.drain(..selected.len().saturating_sub(number));
+        }
+        Ok(selected)
+    }
+
+    /// Decide what to do about an error reading the log, returning how long to wait before trying
+    /// again.
+    ///
+    /// Once we're following, errors are ridden out until there are too many in a row.
+    fn retry(
+        &self,
+        context: &mut Context,
+        backoff: &mut follow::Backoff,
+        connected: bool,
+        e: vapix::Error,
+    ) -> Result<Duration, Error> {
+        if !(self.follow && connected) {
+            return Err(e.into());
+        }
+
+        let (failures, delay) = backoff.failed();
+        if matches!(self.max_failures, Some(max) if failures >= max) {
+            return Err(e.into());
+        }
+
+        context.output(follow::PollFailed {
+            error: e.to_string(),
+            failures,
+            retry_in: delay.as_secs_f64(),
+        })?;
+        Ok(delay)
+    }
+
+    /// Follow the logs of several devices, interleaving their entries by timestamp.
+    ///
+    /// Returns each device's name and exit code. A device which fails is dropped from the stream
+    /// while the others continue.
+    pub async fn follow_merged(&self, contexts: Vec<Context>) -> Vec<(Option<String>, i32)> {
+        let mut outcomes = Vec::new();
+        let mut devices: Vec<Device> = contexts
+            .into_iter()
+            .map(|context| Device {
+                context,
+                cursor: follow::Cursor::default(),
+                backoff: follow::Backoff::new(self.interval),
+                connected: false,
+                number: self.number,
+                retry_at: Instant::now(),
             })
             .collect();
 
-        keepers.reverse();
-        (Self(keepers), resume_at.or(previous))
+        if let Err(e) = self.merge_devices(&mut devices, &mut outcomes).await {
+            // Problems with the output or the syslog collector affect every device
+            eprintln!("{}", e);
+            outcomes.extend(devices.into_iter().map(|d| (d.context.target.name, 1)));
+        }
+
+        outcomes
+    }
+
+    async fn merge_devices(
+        &self,
+        devices: &mut Vec<Device>,
+        outcomes: &mut Vec<(Option<String>, i32)>,
+    ) -> Result<(), Error> {
+        let mut sinks = self.sinks().await?;
+        let fields = self.fields();
+        let format = self.format.as_ref();
+
+        while !devices.is_empty() {
+            // Poll every device which isn't waiting to retry, up to --parallel at once
+            let parallel = devices[0].context.global_options.parallel.max(1);
+            let now = Instant::now();
+            let mut polls: Vec<(usize, Option<_>)> =
+                stream::iter(devices.iter().enumerate().map(|(i, device)| async move {
+                    if device.retry_at > now {
+                        return (i, None);
+                    }
+                    let client = device.context.client();
+                    let result = client.system_log().entries().await;
+                    (i, Some(result))
+                }))
+                .buffer_unordered(parallel)
+                .collect()
+                .await;
+            polls.sort_by_key(|(i, _)| *i);
+            let polls = polls.into_iter().map(|(_, poll)| poll);
+
+            // Sort out which devices succeeded, which will be retried, and which have failed
+            let mut buffers = Vec::with_capacity(devices.len());
+            let mut failed = Vec::new();
+            for (i, (device, poll)) in devices.iter_mut().zip(polls).enumerate() {
+                buffers.push(match poll {
+                    Some(Ok(buffer)) => {
+                        if let Some(outage) = device.backoff.succeeded() {
+                            device.context.output(follow::Reconnected {
+                                after: outage.as_secs_f64(),
+                            })?;
+                        }
+                        device.connected = true;
+                        Some(line::Buffer::new(buffer))
+                    }
+                    Some(Err(e)) => {
+                        match self.retry(
+                            &mut device.context,
+                            &mut device.backoff,
+                            device.connected,
+                            e,
+                        ) {
+                            Ok(delay) => device.retry_at = Instant::now() + delay,
+                            Err(e) => failed.push((i, e)),
+                        }
+                        None
+                    }
+                    None => None,
+                });
+            }
+
+            // Gather everything new, in order
+            let mut merged = Vec::new();
+            for (i, (device, buffer)) in devices.iter_mut().zip(&buffers).enumerate() {
+                if let Some(buffer) = buffer {
+                    let (new, gap) = device.cursor.advance(buffer);
+                    if let Some(gap) = gap {
+                        device.context.output(gap)?;
+                    }
+                    match self.select(new, &mut device.number) {
+                        Ok(selected) => merged.extend(selected.into_iter().map(|e| (i, e))),
+                        Err(e) => failed.push((i, e)),
+                    }
+                }
+            }
+            follow::merge(&mut merged);
+
+            let lines: Vec<Sourced> = merged
+                .iter()
+                .map(|(i, e)| Sourced {
+                    device: devices[*i].context.target.name.as_deref(),
+                    line: e.clone(),
+                })
+                .collect();
+            sinks.send(&lines).await?;
+
+            for (i, entry) in merged {
+                devices[i]
+                    .context
+                    .output(Entry::new(entry, &fields, format))?;
+            }
+
+            // Drop failed devices, latest first so the indices stay valid. Polling and selecting
+            // each add failures, so they're out of order, and a device is only dropped once
+            failed.sort_by_key(|(i, _)| *i);
+            failed.dedup_by_key(|(i, _)| *i);
+            for (i, e) in failed.into_iter().rev() {
+                let device = devices.remove(i);
+                match &device.context.target.name {
+                    Some(name) => eprintln!("{}: {}", name, e),
+                    None => eprintln!("{}", e),
+                }
+                outcomes.push((device.context.target.name, 1));
+            }
+
+            tokio::time::delay_for(self.interval).await;
+        }
+
+        Ok(())
     }
-}
 
-impl Log {
     pub async fn invoke(&self, context: &mut Context) -> Result<(), Error> {
+        if let Some(path) = &self.replay {
+            return self.replay(context, path).await;
+        }
+
         let client = context.client();
         let system_log = client.system_log();
 
         let mut number = self.number;
-        let mut previous = None;
+        let mut cursor = follow::Cursor::default();
+        let mut backoff = follow::Backoff::new(self.interval);
+        let mut connected = false;
+
+        let mut sinks = self.sinks().await?;
 
         loop {
             // Get the log
-            let buffer = system_log.entries().await?;
-            let fields = Fields {
-                timestamp: true,
-                hostname: false,
-                level: true,
-                source: true,
+            let buffer = match system_log.entries().await {
+                Ok(buffer) => {
+                    if let Some(outage) = backoff.succeeded() {
+                        context.output(follow::Reconnected {
+                            after: outage.as_secs_f64(),
+                        })?;
+                    }
+                    connected = true;
+                    line::Buffer::new(buffer)
+                }
+                Err(e) => {
+                    let delay = self.retry(context, &mut backoff, connected, e)?;
+                    tokio::time::delay_for(delay).await;
+                    continue;
+                }
             };
 
-            let (entries, hash) = Entries::new(&buffer, &fields, number.take(), previous);
-
-            if !entries.0.is_empty() {
-                context.output(entries)?;
+            let (new, gap) = cursor.advance(&buffer);
+            if let Some(gap) = gap {
+                context.output(gap)?;
             }
-            previous = hash;
+
+            let selected = self.select(new, &mut number)?;
+            self.emit(context, selected, &mut sinks).await?;
 
             if self.follow {
-                tokio::time::delay_for(Duration::from_millis(500)).await;
+                tokio::time::delay_for(self.interval).await;
                 continue;
             } else {
                 break;
@@ -220,3 +970,121 @@ impl Log {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times() {
+        let absolute = |s: &str| match parse_time(s) {
+            Ok(Time::Absolute(t)) => t,
+            other => panic!("{:?} parsed as {:?}", s, other),
+        };
+        let naive = |s: &str| match parse_time(s) {
+            Ok(Time::Naive(t)) => t.to_string(),
+            other => panic!("{:?} parsed as {:?}", s, other),
+        };
+
+        assert_eq!(
+            absolute("2020-10-10T12:30:00+02:00").to_rfc3339(),
+            "2020-10-10T12:30:00+02:00"
+        );
+        assert_eq!(naive("2020-10-10T12:30:00.5"), "2020-10-10 12:30:00.500");
+        assert_eq!(naive("2020-10-10 12:30:00"), "2020-10-10 12:30:00");
+        assert_eq!(naive("2020-10-10T12:30"), "2020-10-10 12:30:00");
+        assert_eq!(naive("2020-10-10 12:30"), "2020-10-10 12:30:00");
+        assert_eq!(naive("2020-10-10"), "2020-10-10 00:00:00");
+
+        let now: DateTime<FixedOffset> = Local::now().into();
+        for (s, seconds) in &[
+            ("0s", 0),
+            ("90s", 90),
+            ("15m", 900),
+            ("2h", 7200),
+            ("1d", 86400),
+        ] {
+            let ago = (now - absolute(s)).num_seconds();
+            assert!(
+                (*seconds - 5..=*seconds).contains(&ago),
+                "{} was {}s ago",
+                s,
+                ago
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_times() {
+        for s in &[
+            "",
+            "m",
+            "15",
+            "15x",
+            "-5m",
+            "1.5h",
+            "15 m",
+            "2020-13-01",
+            "2020-10-10T25:00",
+            "10/10/2020",
+            "9223372036854775807s",
+            "99999999999999d",
+            "15\u{e9}",
+        ] {
+            assert!(parse_time(s).is_err(), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn templates() {
+        use Segment::*;
+
+        let template: Template = "{timestamp} [{level}] {{{message}}}".parse().unwrap();
+        assert_eq!(
+            template.0,
+            vec![
+                Field(super::Field::Timestamp),
+                Literal(" [".into()),
+                Field(super::Field::Level),
+                Literal("] {".into()),
+                Field(super::Field::Message),
+                Literal("}".into()),
+            ]
+        );
+        assert_eq!(
+            template.fields().collect::<Vec<_>>(),
+            vec![
+                super::Field::Timestamp,
+                super::Field::Level,
+                super::Field::Message
+            ]
+        );
+
+        assert_eq!("".parse::<Template>().unwrap().0, vec![]);
+        assert_eq!(
+            "{{}}".parse::<Template>().unwrap().0,
+            vec![Literal("{}".into())]
+        );
+        assert_eq!(
+            "{hostname}{source}".parse::<Template>().unwrap().0,
+            vec![Field(super::Field::Hostname), Field(super::Field::Source)]
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for s in &[
+            "{}",
+            "{host}",
+            "{ level }",
+            "{Level}",
+            "{level",
+            "level}",
+            "{{level}",
+            "{message}}",
+            "{{message}",
+        ] {
+            assert!(s.parse::<Template>().is_err(), "{:?} parsed", s);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Saving log entries to a file and reading them back, so they can be examined without a device.
+//!
+//! A capture holds one JSON `system_log::Entry` per line. Lines of the log which could not be
+//! parsed are saved as `{"message": "...", "unparsed": true}`. Lines from a device named in the
+//! inventory also say which device, as `"device": "..."`.
+
+use super::line::{Line, Sourced};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use vapix::v3::system_log::{self, Level, Source, Timestamp};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error opening capture {0:?}: {1}")]
+    OpenError(PathBuf, std::io::Error),
+    #[error("error writing capture {0:?}: {1}")]
+    WriteError(PathBuf, std::io::Error),
+    #[error("error reading capture {0:?}: {1}")]
+    ReadError(PathBuf, std::io::Error),
+    #[error("error parsing capture {0:?} at line {1}: {2}")]
+    ParseError(PathBuf, usize, serde_json::Error),
+}
+
+/// A file to which entries are appended as they're printed.
+pub struct Capture {
+    path: PathBuf,
+    file: std::fs::File,
+}
+
+impl Capture {
+    /// Open a capture, creating it if needed.
+    ///
+    /// Entries are appended, so several devices can save to the same capture at once.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::OpenError(path.to_owned(), e))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+        })
+    }
+
+    pub fn save(&mut self, lines: &[Sourced]) -> Result<(), Error> {
+        #[derive(Serialize)]
+        struct Tagged<'a, T> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            device: Option<&'a str>,
+            #[serde(flatten)]
+            line: T,
+        }
+
+        let mut buf = Vec::new();
+        for Sourced { device, line } in lines {
+            let device = *device;
+            match line {
+                Line::Entry(entry) => serde_json::to_writer(
+                    &mut buf,
+                    &Tagged {
+                        device,
+                        line: entry,
+                    },
+                ),
+                Line::Unparsed(message) => serde_json::to_writer(
+                    &mut buf,
+                    &Tagged {
+                        device,
+                        line: Saved::Unparsed {
+                            message: (*message).to_owned(),
+                            unparsed: true,
+                        },
+                    },
+                ),
+            }
+            .expect("lines serialize");
+            buf.push(b'\n');
+        }
+
+        // Write the whole batch at once so it isn't interleaved with another device's
+        self.file
+            .write_all(&buf)
+            .map_err(|e| Error::WriteError(self.path.clone(), e))
+    }
+}
+
+/// A saved line, which owns its strings since they may have been escaped.
+#[derive(Debug, Deserialize)]
+pub struct Record {
+    /// The device the line came from, if it was named
+    #[serde(default)]
+    device: Option<String>,
+    #[serde(flatten)]
+    line: Saved,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Saved {
+    Entry {
+        timestamp: Timestamp,
+        hostname: String,
+        level: Level,
+        source: RecordSource,
+        message: String,
+    },
+    Unparsed {
+        message: String,
+        unparsed: bool,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum RecordSource {
+    None,
+    Name(String),
+    NameAndPid(String, u32),
+}
+
+impl Record {
+    pub fn sourced(&self) -> Sourced<'_> {
+        Sourced {
+            device: self.device.as_deref(),
+            line: self.line(),
+        }
+    }
+
+    pub fn line(&self) -> Line<'_> {
+        match &self.line {
+            Saved::Entry {
+                timestamp,
+                hostname,
+                level,
+                source,
+                message,
+            } => Line::Entry(system_log::Entry {
+                timestamp: *timestamp,
+                hostname,
+                level: *level,
+                source: match source {
+                    RecordSource::None => Source::None,
+                    RecordSource::Name(name) => Source::Name(name),
+                    RecordSource::NameAndPid(name, pid) => Source::NameAndPid(name, *pid),
+                },
+                message,
+            }),
+            Saved::Unparsed { message, .. } => Line::Unparsed(message),
+        }
+    }
+}
+
+/// Read every line from a capture, in the order they were saved.
+pub fn load(path: &Path) -> Result<Vec<Record>, Error> {
+    let file = std::fs::File::open(path).map_err(|e| Error::OpenError(path.to_owned(), e))?;
+
+    let mut records = Vec::new();
+    for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| Error::ReadError(path.to_owned(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(
+            serde_json::from_str(&line)
+                .map_err(|e| Error::ParseError(path.to_owned(), i + 1, e))?,
+        );
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{line, Error as LogError, Log};
+    use super::*;
+    use crate::cli::Context;
+    use chrono::Local;
+    use clap::Clap;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/cli/log/fixtures/capture.jsonl"
+    );
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("axctl-capture-{}.jsonl", uuid::Uuid::new_v4()))
+    }
+
+    /// Output written through a `Context`, which the test keeps a handle on.
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run `axctl log --replay <capture>`, returning what it writes.
+    async fn run(capture: &str, args: &[&str], is_tty: bool) -> Result<String, LogError> {
+        let log = Log::try_parse_from(["log", "--replay", capture].iter().chain(args)).unwrap();
+        let written = Written::default();
+        let mut context = Context::for_test(&[], Box::new(written.clone()), is_tty);
+        log.invoke(&mut context).await?;
+
+        let written = written.0.lock().unwrap();
+        Ok(String::from_utf8(written.clone()).unwrap())
+    }
+
+    /// Replay the fixture, returning what it prints as JSON.
+    async fn replay(args: &[&str]) -> Result<Value, LogError> {
+        let written = run(FIXTURE, args, false).await?;
+        Ok(match written.as_str() {
+            "" => Value::Null,
+            written => serde_json::from_str(written).unwrap(),
+        })
+    }
+
+    /// Replay the fixture, returning what it prints on a terminal without the colors.
+    async fn terminal(args: &[&str]) -> String {
+        let written = run(FIXTURE, args, true).await.unwrap();
+        let escapes = regex::Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        escapes.replace_all(&written, "").into_owned()
+    }
+
+    fn messages(report: &Value) -> Vec<&str> {
+        report
+            .as_array()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|entry| entry["message"].as_str().unwrap())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn entries_survive_saving() {
+        let buffer = line::Buffer::new(system_log::Entries::new(
+            "<INFO    > Oct 10 12:30:00 axis-accc8e7a1b2c sshd[123]: \"quoted\"\r\n\
+             Traceback (most recent call last):\r\n\
+             <WARNING > Oct 10 12:30:01 axis-accc8e7a1b2c kernel: second\r\n\
+             <NOTICE  > Oct 10 12:30:02 axis-accc8e7a1b2c : no source\r\n"
+                .into(),
+            Local::now().into(),
+        ));
+        let lines: Vec<Sourced> = buffer
+            .lines()
+            .into_iter()
+            .zip([Some("lobby"), None].iter().cycle())
+            .map(|(line, device)| Sourced {
+                device: *device,
+                line,
+            })
+            .collect();
+        assert!(lines.iter().any(|s| matches!(s.line, Line::Unparsed(_))));
+
+        let path = temp_path();
+        Capture::open(&path).unwrap().save(&lines).unwrap();
+        let records = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded: Vec<Sourced> = records.iter().map(Record::sourced).collect();
+        assert_eq!(loaded, lines);
+    }
+
+    #[test]
+    fn captures_survive_saving() {
+        let records = load(Path::new(FIXTURE)).unwrap();
+        let lines: Vec<Sourced> = records.iter().map(Record::sourced).collect();
+
+        let path = temp_path();
+        Capture::open(&path).unwrap().save(&lines).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let fixture = std::fs::read_to_string(FIXTURE).unwrap();
+        let fixture: Vec<&str> = fixture.lines().filter(|l| !l.is_empty()).collect();
+        assert_eq!(saved.lines().collect::<Vec<_>>(), fixture);
+    }
+
+    #[tokio::test]
+    async fn replay_says_which_device() {
+        let records = load(Path::new(FIXTURE)).unwrap();
+        let lines: Vec<Sourced> = records
+            .iter()
+            .map(Record::line)
+            .zip(["lobby", "dock"].iter().cycle())
+            .map(|(line, device)| Sourced {
+                device: Some(device),
+                line,
+            })
+            .take(2)
+            .collect();
+        let path = temp_path();
+        Capture::open(&path).unwrap().save(&lines).unwrap();
+
+        let capture = path.to_str().unwrap();
+        let json = run(capture, &["--fields", "level"], false).await.unwrap();
+        let printed = run(capture, &["--format", "{message}"], true)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<Value>(&json).unwrap(),
+            json!([
+                {"device": "lobby", "level": "info", "message": "Accepted password for root"},
+                {
+                    "device": "dock",
+                    "level": "warning",
+                    "message": "usb 1-1: device not accepting address"
+                }
+            ])
+        );
+        let escapes = regex::Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        assert_eq!(
+            escapes.replace_all(&printed, ""),
+            "lobby Accepted password for root\n\
+             dock usb 1-1: device not accepting address\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_filters() {
+        let report = replay(&[]).await.unwrap();
+        assert_eq!(messages(&report).len(), 7);
+
+        let report = replay(&["--level", "warning"]).await.unwrap();
+        assert_eq!(
+            messages(&report),
+            [
+                "usb 1-1: device not accepting address",
+                "frame dropped",
+                "frame dropped"
+            ]
+        );
+
+        let report = replay(&["-s", "sshd", "-s", "dbus-daemon"]).await.unwrap();
+        assert_eq!(
+            messages(&report),
+            [
+                "Accepted password for root",
+                "session closed",
+                "Activating service"
+            ]
+        );
+
+        // Unparsed lines only have a message, so that's all they can be matched on
+        let report = replay(&["-g", "^(frame|Traceback)"]).await.unwrap();
+        assert_eq!(
+            messages(&report),
+            [
+                "frame dropped",
+                "Traceback (most recent call last):",
+                "frame dropped"
+            ]
+        );
+
+        let report = replay(&["--since", "2020-10-10 12:31", "--until", "2020-10-10 12:32"])
+            .await
+            .unwrap();
+        assert_eq!(messages(&report), ["frame dropped", "frame dropped"]);
+
+        let report = replay(&["--since", "2020-10-10T12:33:30-05:00"])
+            .await
+            .unwrap();
+        assert_eq!(messages(&report), ["Activating service"]);
+
+        let report = replay(&["-n", "2"]).await.unwrap();
+        assert_eq!(messages(&report), ["session closed", "Activating service"]);
+
+        let report = replay(&["-l", "emerg"]).await.unwrap();
+        let printed = terminal(&["-l", "emerg"]).await;
+        assert_eq!(report, Value::Null);
+        assert_eq!(printed, "");
+    }
+
+    #[tokio::test]
+    async fn replay_fields_and_format() {
+        let report = replay(&["-s", "kernel"]).await.unwrap();
+        let printed = terminal(&["-s", "kernel"]).await;
+        assert_eq!(
+            report,
+            json!([{
+                "timestamp": "2020-10-10T12:30:05",
+                "level": "warning",
+                "source": "kernel",
+                "message": "usb 1-1: device not accepting address"
+            }])
+        );
+        assert_eq!(
+            printed,
+            format!(
+                "2020-10-10T12:30:05 {} kernel usb 1-1: device not accepting address\n",
+                Level::Warning
+            )
+        );
+
+        let report = replay(&["-s", "kernel", "--fields", "hostname,level"])
+            .await
+            .unwrap();
+        let printed = terminal(&["-s", "kernel", "--fields", "hostname,level"]).await;
+        assert_eq!(
+            report,
+            json!([{
+                "hostname": "axis-accc8e7a1b2c",
+                "level": "warning",
+                "message": "usb 1-1: device not accepting address"
+            }])
+        );
+        assert_eq!(
+            printed,
+            format!(
+                "axis-accc8e7a1b2c {} usb 1-1: device not accepting address\n",
+                Level::Warning
+            )
+        );
+
+        let report = replay(&[
+            "-g",
+            "^T|Accepted",
+            "--format",
+            "{{{level}}} {source}: {message}",
+        ])
+        .await
+        .unwrap();
+        let printed = terminal(&[
+            "-g",
+            "^T|Accepted",
+            "--format",
+            "{{{level}}} {source}: {message}",
+        ])
+        .await;
+        assert_eq!(
+            report,
+            json!([
+                {
+                    "level": "info",
+                    "source": ["sshd", 123],
+                    "message": "Accepted password for root",
+                    "formatted": format!("{{{}}} sshd[123]: Accepted password for root", Level::Info)
+                },
+                {
+                    "message": "Traceback (most recent call last):",
+                    "formatted": "{} : Traceback (most recent call last):",
+                    "unparsed": true
+                }
+            ])
+        );
+        assert_eq!(
+            printed,
+            format!(
+                "{{{}}} sshd[123]: Accepted password for root\n\
+                 {{}} : Traceback (most recent call last):\n",
+                Level::Info
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_stats() {
+        let report = replay(&["--stats"]).await.unwrap();
+        let printed = terminal(&["--stats"]).await;
+        assert_eq!(
+            report,
+            json!({
+                "entries": 6,
+                "unparsed": 1,
+                "first": "2020-10-10T12:30:00",
+                "last": "2020-10-10T12:34:00.123-05:00",
+                "levels": {"error": 2, "warning": 1, "notice": 1, "info": 1, "debug": 1},
+                "sources": [
+                    {"source": "sshd", "count": 2},
+                    {"source": "vdo", "count": 2},
+                    {"source": "kernel", "count": 1},
+                    {"source": "dbus-daemon", "count": 1}
+                ],
+                "messages": [{"message": "frame dropped", "count": 2}]
+            })
+        );
+        assert!(printed.contains("frame dropped"));
+
+        // The summary is of the entries which were selected
+        let report = replay(&["--stats", "-s", "vdo"]).await.unwrap();
+        assert_eq!(report["entries"], 2);
+        assert_eq!(report["unparsed"], 0);
+        assert_eq!(report["sources"], json!([{"source": "vdo", "count": 2}]));
+    }
+
+    #[tokio::test]
+    async fn replay_unparsed_lines() {
+        let report = replay(&["-g", "Traceback"]).await.unwrap();
+        let printed = terminal(&["-g", "Traceback"]).await;
+        assert_eq!(
+            report,
+            json!([{"message": "Traceback (most recent call last):", "unparsed": true}])
+        );
+        assert_eq!(printed, "Traceback (most recent call last):\n");
+
+        match replay(&["--strict"]).await {
+            Err(LogError::UnparsedLine(line)) => {
+                assert_eq!(line, "Traceback (most recent call last):")
+            }
+            other => panic!("expected an unparsed line, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_saves_what_it_selects() {
+        let path = temp_path();
+        let save = path.to_str().unwrap();
+        replay(&["-l", "err", "--save", save]).await.unwrap();
+        replay(&["-g", "Traceback", "--save", save]).await.unwrap();
+        let records = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<Line> = records.iter().map(Record::line).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(matches!(&lines[0], Line::Entry(entry) if entry.message == "frame dropped"));
+        assert!(matches!(&lines[1], Line::Entry(entry) if entry.message == "frame dropped"));
+        assert_eq!(
+            lines[2],
+            Line::Unparsed("Traceback (most recent call last):")
+        );
+    }
+}
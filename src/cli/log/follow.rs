@@ -0,0 +1,313 @@
+//! Tracking our position in a device's log from one poll to the next.
+
+use super::line::{Buffer, Line};
+use crate::output::Output;
+use chrono::{Local, NaiveDateTime, TimeZone};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::time::{Duration, Instant};
+use vapix::v3::system_log::Timestamp;
+
+/// The number of recent entries to remember, which should exceed the size of a device's log.
+const WINDOW: usize = 10_000;
+
+/// Hash lines given newest first.
+///
+/// An unparsed line is only its text, which may well repeat, i.e. in the lines of a stack trace.
+/// So it's hashed along with the line before it, which tells repeats apart.
+fn hashes(lines: &[Line]) -> Vec<u64> {
+    let mut previous = None;
+    let mut hashes: Vec<u64> = lines
+        .iter()
+        .rev()
+        .map(|line| {
+            let mut h = DefaultHasher::new();
+            if let Line::Unparsed(_) = line {
+                previous.hash(&mut h);
+            }
+            line.hash(&mut h);
+            let hash = h.finish();
+            previous = Some(hash);
+            hash
+        })
+        .collect();
+    hashes.reverse();
+    hashes
+}
+
+/// A discontinuity in the log, after which some entries may have been missed.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gap {
+    /// The newest entry we had seen before the gap
+    pub after: Timestamp,
+    /// The oldest entry available after the gap
+    pub before: Option<Timestamp>,
+    /// The number of entries from before `after` which we had not seen, and so did not print
+    pub skipped: usize,
+}
+
+impl Output for Gap {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        let missed = match self.skipped {
+            0 => "lines".to_owned(),
+            1 => "1 line".to_owned(),
+            n => format!("{} lines", n),
+        };
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Yellow),
+            Print(format!(
+                " => lost our place in the log after {}; {} may have been missed",
+                self.after, missed
+            )),
+            ResetColor,
+            Print("\n"),
+        )
+    }
+}
+
+/// Our position in the log, as a window of recently seen entries.
+#[derive(Debug, Default)]
+pub struct Cursor {
+    window: VecDeque<u64>,
+    seen: HashSet<u64>,
+    newest: Option<Timestamp>,
+}
+
+impl Cursor {
+    fn remember(&mut self, hash: u64) {
+        if self.seen.insert(hash) {
+            self.window.push_back(hash);
+            if self.window.len() > WINDOW {
+                if let Some(oldest) = self.window.pop_front() {
+                    self.seen.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /// Return the lines which are new since the last call, oldest first.
+    ///
+    /// Lines are new if they follow the most recent line we've seen. If none of the lines we've
+    /// seen remain, e.g. because the log was rotated or more than a buffer's worth of lines arrived
+    /// since the last call, lines are new if they follow the newest timestamp we've seen, and the
+    /// discontinuity is reported as a `Gap`.
+    pub fn advance<'a>(&mut self, buffer: &'a Buffer) -> (Vec<Line<'a>>, Option<Gap>) {
+        // Newest first
+        let lines = buffer.lines();
+        let entries: Vec<(u64, Line)> = hashes(&lines).into_iter().zip(lines).collect();
+
+        // An empty log tells us nothing, e.g. immediately after rotation
+        if entries.is_empty() {
+            return (Vec::new(), None);
+        }
+
+        let (mut new, gap) = match (
+            self.newest,
+            entries.iter().position(|(h, _)| self.seen.contains(h)),
+        ) {
+            // Everything is new the first time
+            (None, _) => (entries, None),
+
+            // Everything after the most recent entry we've seen is new
+            (Some(_), Some(i)) => (entries.into_iter().take(i).collect(), None),
+
+            // We've lost our place, so go by timestamp instead
+            (Some(newest), None) => {
+                let is_newer = |t: &Timestamp| match t.partial_cmp(&newest) {
+                    Some(Ordering::Less) | Some(Ordering::Equal) => false,
+                    Some(Ordering::Greater) | None => true,
+                };
+                let before = entries.iter().rev().find_map(|(_, e)| e.timestamp());
+
+                // Lines without a timestamp go with the entries around them
+                let mut new = entries;
+                let split = new
+                    .iter()
+                    .position(|(_, e)| matches!(e.timestamp(), Some(t) if !is_newer(&t)))
+                    .unwrap_or(new.len());
+                let old = new.split_off(split);
+                let gap = Gap {
+                    after: newest,
+                    before,
+                    skipped: old.len(),
+                };
+                (new, Some(gap))
+            }
+        };
+
+        new.reverse();
+        for (hash, entry) in &new {
+            self.remember(*hash);
+            if let Some(timestamp) = entry.timestamp() {
+                self.newest = Some(timestamp);
+            }
+        }
+
+        (new.into_iter().map(|(_, entry)| entry).collect(), gap)
+    }
+}
+
+/// The instant at which an entry was logged, in UTC.
+///
+/// Timestamps without an offset are assumed to be in our local time zone.
+fn utc(timestamp: &Timestamp) -> Option<NaiveDateTime> {
+    match timestamp {
+        Timestamp::FixedOffset(t) => Some(t.naive_utc()),
+        Timestamp::Naive(t) => Local
+            .from_local_datetime(t)
+            .earliest()
+            .map(|t| t.naive_utc()),
+    }
+}
+
+/// Order lines from several devices by when they were logged, given each device's lines in order.
+///
+/// The sort is stable, so lines from one device which share a timestamp stay in order. Lines
+/// without a timestamp stay with the line before them.
+pub fn merge(lines: &mut Vec<(usize, Line)>) {
+    let mut previous: HashMap<usize, Option<NaiveDateTime>> = HashMap::new();
+    let mut keyed: Vec<(Option<NaiveDateTime>, (usize, Line))> = lines
+        .drain(..)
+        .map(|(device, line)| {
+            let key = match line.timestamp() {
+                Some(t) => utc(&t),
+                None => previous.get(&device).copied().flatten(),
+            };
+            previous.insert(device, key);
+            (key, (device, line))
+        })
+        .collect();
+
+    keyed.sort_by_key(|(key, _)| *key);
+    lines.extend(keyed.into_iter().map(|(_, line)| line));
+}
+
+/// The longest we'll wait between attempts to read the log.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// An unsuccessful attempt to read the log while following it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PollFailed {
+    pub error: String,
+    /// The number of consecutive failures, including this one
+    pub failures: u32,
+    /// Seconds until the next attempt
+    pub retry_in: f64,
+}
+
+impl Output for PollFailed {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Yellow),
+            Print(format!(
+                " => error reading the log ({} in a row): {}; retrying in {:.1}s",
+                self.failures, self.error, self.retry_in
+            )),
+            ResetColor,
+            Print("\n"),
+        )
+    }
+}
+
+/// A successful attempt to read the log after one or more failures.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reconnected {
+    /// Seconds since the first failure
+    pub after: f64,
+}
+
+impl Output for Reconnected {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            Print(format!(" => reconnected after {:.1}s\n", self.after))
+        )
+    }
+}
+
+/// Exponential backoff with jitter, for retrying after errors.
+#[derive(Debug)]
+pub struct Backoff {
+    interval: Duration,
+    failures: u32,
+    first_failure: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            failures: 0,
+            first_failure: None,
+        }
+    }
+
+    /// Record a failure, returning the number of consecutive failures and how long to wait.
+    ///
+    /// The wait doubles with each failure up to `MAX_BACKOFF`, and is then randomized to between
+    /// half and all of that so that many clients don't retry in lockstep.
+    pub fn failed(&mut self) -> (u32, Duration) {
+        use rand::Rng;
+
+        self.failures += 1;
+        self.first_failure.get_or_insert_with(Instant::now);
+
+        let ceiling = (1..self.failures)
+            .try_fold(self.interval, |delay, _| delay.checked_mul(2))
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF)
+            .max(Duration::from_millis(1));
+        let millis = ceiling.as_millis() as u64;
+        let delay = Duration::from_millis(rand::thread_rng().gen_range(millis / 2, millis + 1));
+
+        (self.failures, delay)
+    }
+
+    /// Record a success, returning how long we were failing, if we were.
+    pub fn succeeded(&mut self) -> Option<Duration> {
+        self.failures = 0;
+        self.first_failure.take().map(|start| start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use vapix::v3::system_log::Entries;
+
+    #[test]
+    fn repeated_unparsed_lines_are_new() {
+        let first = "<INFO    > Oct 10 12:30:00 axis-accc8e7a1b2c app[1]: failed\n\
+                     \tat frame\n\
+                     \tat frame\n";
+        let second = format!("{}\tat frame\n", first);
+        let buffer = |text: &str| Buffer::new(Entries::new(text.into(), Local::now().into()));
+
+        let mut cursor = Cursor::default();
+        let first = buffer(first);
+        let (new, gap) = cursor.advance(&first);
+        assert_eq!(new.len(), 3);
+        assert!(gap.is_none());
+
+        let second = buffer(&second);
+        let (new, gap) = cursor.advance(&second);
+        assert_eq!(new, vec![Line::Unparsed("\tat frame")]);
+        assert!(gap.is_none());
+    }
+}
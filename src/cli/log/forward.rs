@@ -0,0 +1,355 @@
+//! Forwarding log entries to a syslog collector as RFC 5424 messages.
+
+use super::line::Line;
+use chrono::{DateTime, FixedOffset, Local, TimeZone};
+use std::net::{SocketAddr, ToSocketAddrs};
+use thiserror::Error;
+use tokio::prelude::*;
+use vapix::v3::system_log::{self, Level, Source, Timestamp};
+
+/// Messages are sent with the "user-level messages" facility.
+const FACILITY: u8 = 1;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error resolving syslog collector {0:?}: {1}")]
+    ResolutionError(String, std::io::Error),
+    #[error("error connecting to syslog collector {0}: {1}")]
+    ConnectError(SocketAddr, std::io::Error),
+    #[error("error sending to syslog collector {0}: {1}")]
+    SendError(SocketAddr, std::io::Error),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// A syslog collector, i.e. `udp://host:514` or `tcp://host:514`.
+#[derive(Debug, Clone)]
+pub struct Destination {
+    protocol: Protocol,
+    host: String,
+    port: u16,
+}
+
+impl std::str::FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri: http::Uri = s.parse().map_err(|e| format!("{}", e))?;
+
+        let protocol = match uri.scheme_str() {
+            Some("udp") => Protocol::Udp,
+            Some("tcp") => Protocol::Tcp,
+            _ => return Err(format!("{:?} must start with udp:// or tcp://", s)),
+        };
+        let host = match uri.host() {
+            Some(host) if uri.path() == "/" || uri.path().is_empty() => host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            _ => {
+                return Err(format!(
+                    "{:?} must be udp://host:port or tcp://host:port",
+                    s
+                ))
+            }
+        };
+
+        Ok(Destination {
+            protocol,
+            host,
+            port: uri.port_u16().unwrap_or(514),
+        })
+    }
+}
+
+enum Connection {
+    Udp(tokio::net::UdpSocket),
+    Tcp(tokio::net::TcpStream),
+}
+
+/// A connection to a syslog collector.
+pub struct Forwarder {
+    protocol: Protocol,
+    addr: SocketAddr,
+    connection: Option<Connection>,
+}
+
+impl Forwarder {
+    pub async fn connect(destination: &Destination) -> Result<Self, Error> {
+        let addr = (destination.host.as_str(), destination.port)
+            .to_socket_addrs()
+            .map_err(|e| Error::ResolutionError(destination.host.clone(), e))?
+            .next()
+            .expect("name resolution produced no addresses");
+
+        let mut forwarder = Self {
+            protocol: destination.protocol,
+            addr,
+            connection: None,
+        };
+        forwarder.open().await?;
+        Ok(forwarder)
+    }
+
+    async fn open(&mut self) -> Result<(), Error> {
+        let addr = self.addr;
+        let connection = match self.protocol {
+            Protocol::Udp => {
+                let local: SocketAddr = if addr.is_ipv4() {
+                    "0.0.0.0:0".parse().unwrap()
+                } else {
+                    "[::]:0".parse().unwrap()
+                };
+                let socket = tokio::net::UdpSocket::bind(local)
+                    .await
+                    .map_err(|e| Error::ConnectError(addr, e))?;
+                socket
+                    .connect(addr)
+                    .await
+                    .map_err(|e| Error::ConnectError(addr, e))?;
+                Connection::Udp(socket)
+            }
+            Protocol::Tcp => Connection::Tcp(
+                tokio::net::TcpStream::connect(addr)
+                    .await
+                    .map_err(|e| Error::ConnectError(addr, e))?,
+            ),
+        };
+
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    /// Send a line to the collector.
+    ///
+    /// If a TCP connection has dropped, this reconnects once before giving up.
+    pub async fn send(&mut self, line: &Line<'_>) -> Result<(), Error> {
+        let message = match line {
+            Line::Entry(entry) => format(entry),
+            Line::Unparsed(text) => format_unparsed(text),
+        };
+
+        match self.send_message(&message).await {
+            Err(_) if self.protocol == Protocol::Tcp => {
+                self.open().await?;
+                self.send_message(&message).await
+            }
+            result => result,
+        }
+    }
+
+    async fn send_message(&mut self, message: &str) -> Result<(), Error> {
+        let addr = self.addr;
+        let result = match &mut self.connection {
+            Some(Connection::Udp(socket)) => socket.send(message.as_bytes()).await.map(|_| ()),
+            Some(Connection::Tcp(stream)) => {
+                // TCP uses octet-counting framing, per RFC 6587
+                let frame = format!("{} {}", message.len(), message);
+                match stream.write_all(frame.as_bytes()).await {
+                    Ok(()) => stream.flush().await,
+                    Err(e) => Err(e),
+                }
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "not connected",
+            )),
+        };
+
+        result.map_err(|e| {
+            self.connection = None;
+            Error::SendError(addr, e)
+        })
+    }
+}
+
+/// The RFC 5424 severity for a log level.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Emergency => 0,
+        Level::Alert => 1,
+        Level::Critical => 2,
+        Level::Error => 3,
+        Level::Warning => 4,
+        Level::Notice => 5,
+        Level::Info => 6,
+        Level::Debug => 7,
+        // "last message repeated" lines carry no severity of their own
+        Level::Repeated => 6,
+    }
+}
+
+/// An RFC 5424 header field: printable ASCII, truncated to `max` characters, or `-` if empty.
+fn header_field(s: &str, max: usize) -> String {
+    let s: String = s
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if s.is_empty() {
+        "-".into()
+    } else {
+        s
+    }
+}
+
+/// Format an entry as an RFC 5424 message.
+///
+/// Timestamps without an offset are assumed to be in our local time zone.
+pub fn format(entry: &system_log::Entry) -> String {
+    let timestamp = match entry.timestamp {
+        Timestamp::FixedOffset(t) => Some(t),
+        Timestamp::Naive(t) => Local
+            .from_local_datetime(&t)
+            .single()
+            .map(DateTime::<FixedOffset>::from),
+    }
+    .map(|t| t.format("%Y-%m-%dT%H:%M:%S%.6f%:z").to_string())
+    .unwrap_or_else(|| "-".into());
+
+    let (app_name, proc_id) = match entry.source {
+        Source::None => ("", None),
+        Source::Name(name) => (name, None),
+        Source::NameAndPid(name, pid) => (name, Some(pid)),
+    };
+
+    format!(
+        "<{}>1 {} {} {} {} - - {}",
+        FACILITY * 8 + severity(entry.level),
+        timestamp,
+        header_field(entry.hostname, 255),
+        header_field(app_name, 48),
+        proc_id
+            .map(|pid| pid.to_string())
+            .unwrap_or_else(|| "-".into()),
+        entry.message,
+    )
+}
+
+/// Format a line which could not be parsed as an RFC 5424 message, with nothing but its text.
+pub fn format_unparsed(text: &str) -> String {
+    format!(
+        "<{}>1 - - - - - - {}",
+        FACILITY * 8 + severity(Level::Info),
+        text
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn entry(level: Level, source: Source<'static>) -> system_log::Entry<'static> {
+        system_log::Entry {
+            timestamp: Timestamp::FixedOffset(
+                FixedOffset::east_opt(2 * 3600)
+                    .unwrap()
+                    .from_local_datetime(
+                        &NaiveDate::from_ymd_opt(2020, 10, 10)
+                            .unwrap()
+                            .and_hms_micro_opt(12, 30, 0, 123_456)
+                            .unwrap(),
+                    )
+                    .unwrap(),
+            ),
+            hostname: "axis-accc8e7a1b2c",
+            level,
+            source,
+            message: "hello",
+        }
+    }
+
+    #[test]
+    fn format_entry() {
+        assert_eq!(
+            format(&entry(Level::Warning, Source::NameAndPid("sshd", 123))),
+            "<12>1 2020-10-10T12:30:00.123456+02:00 axis-accc8e7a1b2c sshd 123 - - hello"
+        );
+    }
+
+    #[test]
+    fn pri_is_user_facility_and_severity() {
+        let pri = |level| format(&entry(level, Source::None))[..5].to_owned();
+        assert_eq!(pri(Level::Emergency), "<8>1 ");
+        assert_eq!(pri(Level::Error), "<11>1");
+        assert_eq!(pri(Level::Info), "<14>1");
+        assert_eq!(pri(Level::Repeated), "<14>1");
+        assert_eq!(pri(Level::Debug), "<15>1");
+    }
+
+    #[test]
+    fn missing_fields_are_nilvalues() {
+        let mut entry = entry(Level::Info, Source::None);
+        entry.hostname = " ";
+        assert!(format(&entry).ends_with(".123456+02:00 - - - - - hello"));
+
+        entry.source = Source::Name("kernel");
+        assert!(format(&entry).ends_with(" - kernel - - - hello"));
+    }
+
+    #[test]
+    fn header_fields_are_printable_and_bounded() {
+        let mut entry = entry(Level::Info, Source::None);
+        entry.hostname = "axis cam\u{e9}ra";
+        entry.source = Source::Name("a-very-long-application-name-which-goes-on-and-on-and-on");
+        assert_eq!(
+            format(&entry),
+            "<14>1 2020-10-10T12:30:00.123456+02:00 axiscamra \
+             a-very-long-application-name-which-goes-on-and-o - - - hello"
+        );
+    }
+
+    #[test]
+    fn naive_timestamps_are_local() {
+        let naive = NaiveDate::from_ymd_opt(2020, 10, 10)
+            .unwrap()
+            .and_hms_opt(12, 30, 0)
+            .unwrap();
+        let mut entry = entry(Level::Info, Source::None);
+        entry.timestamp = Timestamp::Naive(naive);
+
+        let local = DateTime::<FixedOffset>::from(Local.from_local_datetime(&naive).unwrap());
+        let expected = local.format("%Y-%m-%dT%H:%M:%S%.6f%:z").to_string();
+        assert!(format(&entry).starts_with(&format!("<14>1 {} ", expected)));
+    }
+
+    #[test]
+    fn structured_data_is_always_nil() {
+        // The message goes after an empty STRUCTURED-DATA, so nothing in it needs escaping
+        let mut entry = entry(Level::Info, Source::None);
+        entry.message = "[origin ip=\"10.0.0.1\"] \\ ]";
+        assert!(format(&entry).ends_with(" - - - [origin ip=\"10.0.0.1\"] \\ ]"));
+    }
+
+    #[test]
+    fn format_unparsed_line() {
+        assert_eq!(
+            format_unparsed("\tat frame"),
+            "<14>1 - - - - - - \tat frame"
+        );
+    }
+
+    #[test]
+    fn destinations() {
+        let parse = |s: &str| {
+            s.parse::<Destination>()
+                .map(|d| (d.protocol, d.host, d.port))
+        };
+        assert_eq!(
+            parse("udp://collector"),
+            Ok((Protocol::Udp, "collector".into(), 514))
+        );
+        assert_eq!(
+            parse("tcp://[::1]:6514"),
+            Ok((Protocol::Tcp, "::1".into(), 6514))
+        );
+        assert!(parse("http://collector").is_err());
+        assert!(parse("collector:514").is_err());
+        assert!(parse("udp://collector/path").is_err());
+    }
+}
@@ -0,0 +1,149 @@
+//! Lines of a device's log, whether or not they could be parsed.
+
+use chrono::{DateTime, FixedOffset, Local};
+use serde::Deserialize;
+use std::ops::Range;
+use vapix::v3::system_log::{self, Timestamp};
+
+/// What's shown for a line which couldn't be parsed when its text couldn't be found either.
+const UNAVAILABLE: &str = "(a line which could not be parsed)";
+
+/// A line of the log.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub enum Line<'a> {
+    Entry(system_log::Entry<'a>),
+    /// A line which could not be parsed, e.g. because the firmware logs in an unfamiliar format
+    Unparsed(&'a str),
+}
+
+impl<'a> Line<'a> {
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        match self {
+            Line::Entry(entry) => Some(entry.timestamp),
+            Line::Unparsed(_) => None,
+        }
+    }
+}
+
+impl<'a> AsRef<Line<'a>> for Line<'a> {
+    fn as_ref(&self) -> &Line<'a> {
+        self
+    }
+}
+
+/// A line of the log, with the name of the device it came from when there's more than one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sourced<'a> {
+    pub device: Option<&'a str>,
+    pub line: Line<'a>,
+}
+
+impl<'a> AsRef<Line<'a>> for Sourced<'a> {
+    fn as_ref(&self) -> &Line<'a> {
+        &self.line
+    }
+}
+
+/// The log as returned by a device.
+pub struct Buffer {
+    entries: system_log::Entries,
+    text: String,
+    /// Where in `text` is each line which `entries` has a result for, newest first, if they could
+    /// be found
+    lines: Option<Vec<Range<usize>>>,
+}
+
+impl Buffer {
+    pub fn new(entries: system_log::Entries) -> Self {
+        // `Entries` keeps the text it parses to itself, but will serialize it. If that changes,
+        // the entries are still there, and only the text of unparsed lines is lost.
+        #[derive(Deserialize)]
+        struct Text {
+            buffer: String,
+        }
+        let text = serde_json::to_value(&entries)
+            .and_then(serde_json::from_value)
+            .map(|text: Text| text.buffer)
+            .unwrap_or_default();
+
+        // Ask `Entries` about each line on its own, so that the lines it passes over (blank lines,
+        // banners and whatever else) are left out here too. The time only matters for parsing
+        // timestamps, not for which lines get a result.
+        let now: DateTime<FixedOffset> = Local::now().into();
+        let lines: Vec<Range<usize>> = text
+            .rsplit('\n')
+            .filter(|line| {
+                system_log::Entries::new((*line).to_owned(), now)
+                    .iter()
+                    .next()
+                    .is_some()
+            })
+            .map(|line| {
+                let start = line.as_ptr() as usize - text.as_ptr() as usize;
+                start..start + line.len()
+            })
+            .collect();
+        let lines = Some(lines).filter(|lines| lines.len() == entries.iter().count());
+
+        Self {
+            entries,
+            text,
+            lines,
+        }
+    }
+
+    /// The lines of the log, newest first.
+    pub fn lines(&self) -> Vec<Line<'_>> {
+        let mut lines = self.lines.iter().flatten();
+        self.entries
+            .iter()
+            .map(|result| (result, lines.next()))
+            .map(|(result, range)| match (result, range) {
+                (Ok(entry), _) => Line::Entry(entry),
+                (Err(_), Some(range)) => {
+                    let text = &self.text[range.clone()];
+                    Line::Unparsed(text.strip_suffix('\r').unwrap_or(text))
+                }
+                (Err(_), None) => Line::Unparsed(UNAVAILABLE),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str) -> Buffer {
+        Buffer::new(system_log::Entries::new(text.into(), Local::now().into()))
+    }
+
+    #[test]
+    fn lines_line_up_with_entries() {
+        let buffer = buffer(
+            "----- Oct 10 12:00:00 -----\r\n\
+             <INFO    > Oct 10 12:30:00 axis-accc8e7a1b2c sshd[123]: first\r\n\
+             \r\n\
+             traceback follows\r\n\
+             <WARNING > Oct 10 12:30:01 axis-accc8e7a1b2c kernel: second\r\n",
+        );
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 3);
+        assert!(matches!(&lines[0], Line::Entry(entry) if entry.message == "second"));
+        assert_eq!(lines[1], Line::Unparsed("traceback follows"));
+        assert!(matches!(&lines[2], Line::Entry(entry) if entry.message == "first"));
+    }
+
+    #[test]
+    fn entries_outlive_missing_text() {
+        let mut buffer = buffer(
+            "<INFO    > Oct 10 12:30:00 axis-accc8e7a1b2c sshd[123]: first\r\n\
+             traceback follows\r\n",
+        );
+        buffer.lines = None;
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], Line::Unparsed(UNAVAILABLE));
+        assert!(matches!(&lines[1], Line::Entry(entry) if entry.message == "first"));
+    }
+}
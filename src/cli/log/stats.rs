@@ -0,0 +1,201 @@
+//! Summarizing a log rather than printing it.
+
+use super::line::Line;
+use crate::output::Output;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use vapix::v3::system_log::{self, Level, Source, Timestamp};
+
+/// The number of repeated messages to report.
+const TOP_MESSAGES: usize = 10;
+
+/// A summary of a log.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub entries: usize,
+    /// The number of lines which could not be parsed as entries
+    pub unparsed: usize,
+    pub first: Option<Timestamp>,
+    pub last: Option<Timestamp>,
+    pub levels: BTreeMap<Level, usize>,
+    /// Processes by number of entries, most first
+    pub sources: Vec<SourceCount>,
+    /// Messages which appear more than once, most first
+    pub messages: Vec<MessageCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceCount {
+    pub source: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessageCount {
+    pub message: String,
+    pub count: usize,
+}
+
+/// Count occurrences, returning the most frequent first and then in order of appearance.
+fn rank<'a>(values: impl Iterator<Item = &'a str>) -> Vec<(&'a str, usize)> {
+    let mut counts: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (i, value) in values.enumerate() {
+        counts.entry(value).or_insert((0, i)).0 += 1;
+    }
+
+    let mut ranked: Vec<(&str, (usize, usize))> = counts.into_iter().collect();
+    ranked.sort_by_key(|(_, (count, first))| (std::cmp::Reverse(*count), *first));
+    ranked
+        .into_iter()
+        .map(|(value, (count, _))| (value, count))
+        .collect()
+}
+
+impl Stats {
+    /// Summarize lines, which are oldest first.
+    pub fn new(lines: &[Line]) -> Self {
+        let entries: Vec<&system_log::Entry> = lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Entry(entry) => Some(entry),
+                Line::Unparsed(_) => None,
+            })
+            .collect();
+
+        let mut levels = BTreeMap::new();
+        for entry in &entries {
+            *levels.entry(entry.level).or_insert(0) += 1;
+        }
+
+        let sources = rank(entries.iter().filter_map(|e| match e.source {
+            Source::None => None,
+            Source::Name(name) | Source::NameAndPid(name, _) => Some(name),
+        }))
+        .into_iter()
+        .map(|(source, count)| SourceCount {
+            source: source.to_owned(),
+            count,
+        })
+        .collect();
+
+        let messages = rank(entries.iter().map(|e| e.message))
+            .into_iter()
+            .take_while(|(_, count)| *count > 1)
+            .take(TOP_MESSAGES)
+            .map(|(message, count)| MessageCount {
+                message: message.to_owned(),
+                count,
+            })
+            .collect();
+
+        Self {
+            entries: entries.len(),
+            unparsed: lines.len() - entries.len(),
+            first: entries.first().map(|e| e.timestamp),
+            last: entries.last().map(|e| e.timestamp),
+            levels,
+            sources,
+            messages,
+        }
+    }
+}
+
+impl Output for Stats {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        fn heading(stdout: &mut dyn Write, heading: &str) -> Result<(), crossterm::ErrorKind> {
+            queue!(stdout, Print(format!("{:>12}: ", heading)))
+        }
+        const INDENT: &str = "              ";
+
+        let timestamp = |t: &Option<Timestamp>| {
+            t.map(|t| t.to_string())
+                .unwrap_or_else(|| "none".to_owned())
+        };
+
+        heading(stdout, "Entries")?;
+        queue!(
+            stdout,
+            SetAttribute(Attribute::Bold),
+            Print(self.entries),
+            SetAttribute(Attribute::NormalIntensity),
+            Print("\n"),
+        )?;
+        heading(stdout, "Unparsed")?;
+        queue!(
+            stdout,
+            SetForegroundColor(if self.unparsed > 0 {
+                Color::Yellow
+            } else {
+                Color::Reset
+            }),
+            Print(self.unparsed),
+            ResetColor,
+            Print("\n"),
+        )?;
+        heading(stdout, "First")?;
+        queue!(stdout, Print(timestamp(&self.first)), Print("\n"))?;
+        heading(stdout, "Last")?;
+        queue!(stdout, Print(timestamp(&self.last)), Print("\n"))?;
+
+        let width = self
+            .levels
+            .values()
+            .chain(self.sources.iter().map(|s| &s.count))
+            .chain(self.messages.iter().map(|m| &m.count))
+            .max()
+            .map(|n| n.to_string().len())
+            .unwrap_or(1);
+
+        heading(stdout, "Levels")?;
+        for (i, (level, count)) in self.levels.iter().enumerate() {
+            queue!(
+                stdout,
+                Print(if i == 0 { "" } else { INDENT }),
+                Print(format!("{:>width$} ", count, width = width)),
+                SetForegroundColor(super::level_color(Some(*level))),
+                Print(level),
+                ResetColor,
+                Print("\n"),
+            )?;
+        }
+        if self.levels.is_empty() {
+            queue!(stdout, Print("none\n"))?;
+        }
+
+        heading(stdout, "Sources")?;
+        for (i, source) in self.sources.iter().enumerate() {
+            queue!(
+                stdout,
+                Print(if i == 0 { "" } else { INDENT }),
+                Print(format!("{:>width$} ", source.count, width = width)),
+                SetForegroundColor(Color::Cyan),
+                Print(&source.source),
+                ResetColor,
+                Print("\n"),
+            )?;
+        }
+        if self.sources.is_empty() {
+            queue!(stdout, Print("none\n"))?;
+        }
+
+        heading(stdout, "Repeated")?;
+        for (i, message) in self.messages.iter().enumerate() {
+            queue!(
+                stdout,
+                Print(if i == 0 { "" } else { INDENT }),
+                Print(format!("{:>width$} ", message.count, width = width)),
+                Print(&message.message),
+                Print("\n"),
+            )?;
+        }
+        if self.messages.is_empty() {
+            queue!(stdout, Print("none\n"))?;
+        }
+
+        Ok(())
+    }
+}
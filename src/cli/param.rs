@@ -0,0 +1,470 @@
+use crate::cli::{Context, Transport};
+use crate::output::Output;
+use clap::Clap;
+use crossterm::ErrorKind;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use thiserror::Error;
+
+/// The most parameters to change in one request, since they all go in the query string.
+pub(super) const BATCH_SIZE: usize = 32;
+
+/// Read and change device parameters
+#[derive(Debug, Clone, Clap)]
+#[clap(setting = clap::AppSettings::VersionlessSubcommands)]
+pub struct Param {
+    #[clap(subcommand)]
+    subcommand: Subcommand,
+}
+
+#[derive(Debug, Clone, Clap)]
+enum Subcommand {
+    /// List parameters, or only those in a group like `Network`
+    List {
+        /// The groups to list
+        groups: Vec<String>,
+    },
+
+    /// Print the values of parameters
+    Get {
+        /// The parameters, i.e. `Network.HostName`
+        #[clap(required = true)]
+        names: Vec<String>,
+    },
+
+    /// Change parameters, showing the difference before changing anything
+    Set {
+        /// The changes, i.e. `Network.HostName=lobby`
+        #[clap(required = true, parse(try_from_str = parse_assignment))]
+        assignments: Vec<(String, String)>,
+
+        /// Show what would change without changing it
+        #[clap(short = 'n', long)]
+        dry_run: bool,
+    },
+}
+
+fn parse_assignment(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) if !name.is_empty() => {
+            Ok((strip_root(name).to_owned(), value.to_owned()))
+        }
+        _ => Err(format!(
+            "{:?} is not an assignment like Network.HostName=lobby",
+            s
+        )),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error writing to terminal: {0}")]
+    TerminalError(#[from] crossterm::ErrorKind),
+    #[error("VAPIX call failed: {0}")]
+    VapixCallFailed(#[from] vapix::Error),
+    #[error("the device has no parameter or group named {0:?}")]
+    UnknownParameter(String),
+    #[error("failed to change parameters after changing {0} of {1}: {2}")]
+    PartialUpdate(usize, usize, vapix::Error),
+}
+
+/// Parameter names may be given with or without the `root.` which devices sometimes include.
+fn strip_root(name: &str) -> &str {
+    name.strip_prefix("root.").unwrap_or(name)
+}
+
+/// List parameters in groups, or all of them if `groups` is empty, with `root.` removed from their
+/// names.
+pub(super) async fn list<S: AsRef<str>>(
+    client: &vapix::Client<Transport>,
+    groups: &[S],
+) -> Result<BTreeMap<String, String>, vapix::Error> {
+    let groups: Vec<&str> = groups.iter().map(|g| strip_root(g.as_ref())).collect();
+    let params = client
+        .parameters()
+        .list(
+            Some(&groups)
+                .filter(|g| !g.is_empty())
+                .map(|g| g.as_slice()),
+        )
+        .await?;
+    Ok(params
+        .into_iter()
+        .map(|(name, value)| (strip_root(&name).to_owned(), value))
+        .collect())
+}
+
+/// A parameter and its value.
+#[derive(Debug, Clone, Serialize)]
+struct Parameter {
+    name: String,
+    value: String,
+}
+
+impl Output for Parameter {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Cyan),
+            Print(&self.name),
+            ResetColor,
+            Print("="),
+            SetAttribute(Attribute::Bold),
+            Print(&self.value),
+            SetAttribute(Attribute::NormalIntensity),
+            Print("\n"),
+        )
+    }
+}
+
+/// A parameter which is to change.
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct Change {
+    pub name: String,
+    /// The current value. `changes()` only deals in parameters which exist, so it always sets
+    /// this; only `apply` leaves it `None`, for parameters of applications it has yet to install.
+    pub old: Option<String>,
+    pub new: String,
+}
+
+impl Output for Change {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Yellow),
+            Print("  ~ "),
+            ResetColor,
+            Print(&self.name),
+            Print(": "),
+            SetForegroundColor(Color::Red),
+            Print(match &self.old {
+                Some(old) => format!("{:?}", old),
+                None => "(unset)".to_owned(),
+            }),
+            ResetColor,
+            Print(" => "),
+            SetForegroundColor(Color::Green),
+            Print(format!("{:?}", self.new)),
+            ResetColor,
+            Print("\n"),
+        )
+    }
+}
+
+/// Work out which assignments would change something, in the order they were given.
+///
+/// Assigning the same parameter more than once uses the last value.
+pub(super) fn changes(
+    current: &BTreeMap<String, String>,
+    assignments: &[(String, String)],
+) -> Result<Vec<Change>, Error> {
+    let mut changes: Vec<Change> = Vec::new();
+    for (name, value) in assignments {
+        let old = current
+            .get(name)
+            .ok_or_else(|| Error::UnknownParameter(name.clone()))?;
+        changes.retain(|c| &c.name != name);
+        if old != value {
+            changes.push(Change {
+                name: name.clone(),
+                old: Some(old.clone()),
+                new: value.clone(),
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// Make changes in batches, so that a long list doesn't make for an overlong request.
+pub(super) async fn update(
+    client: &vapix::Client<Transport>,
+    changes: &[Change],
+) -> Result<(), Error> {
+    let parameters = client.parameters();
+    for (i, batch) in changes.chunks(BATCH_SIZE).enumerate() {
+        parameters
+            .update(batch.iter().map(|c| (&c.name, &c.new)))
+            .await
+            .map_err(|e| match i {
+                0 => Error::VapixCallFailed(e),
+                _ => Error::PartialUpdate(i * BATCH_SIZE, changes.len(), e),
+            })?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Updated {
+    changed: usize,
+    dry_run: bool,
+}
+
+impl Output for Updated {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        let plural = if self.changed == 1 { "" } else { "s" };
+        let message = match (self.changed, self.dry_run) {
+            (0, _) => " => nothing to change\n".to_owned(),
+            (n, true) => format!(" => {} parameter{} would change\n", n, plural),
+            (n, false) => format!(" => changed {} parameter{}\n", n, plural),
+        };
+        queue!(stdout, Print(message))
+    }
+}
+
+impl Param {
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        let client = context.client();
+
+        match self.subcommand {
+            Subcommand::List { groups } => {
+                let params = list(&client, &groups).await?;
+                // Devices answer for groups they don't have with an error message in place of
+                // parameters, which doesn't parse as any
+                if params.is_empty() && !groups.is_empty() {
+                    return Err(Error::UnknownParameter(groups.join(",")));
+                }
+                for (name, value) in params {
+                    context.output(Parameter { name, value })?;
+                }
+            }
+            Subcommand::Get { names } => {
+                let params = list(&client, &names).await?;
+                for name in &names {
+                    let name = strip_root(name);
+                    match params.get(name) {
+                        Some(value) => context.output(Parameter {
+                            name: name.to_owned(),
+                            value: value.clone(),
+                        })?,
+                        // Asking for a group gets everything in it
+                        None => {
+                            let prefix = format!("{}.", name);
+                            let group: Vec<(&String, &String)> = params
+                                .iter()
+                                .filter(|(n, _)| n.starts_with(&prefix))
+                                .collect();
+                            if group.is_empty() {
+                                return Err(Error::UnknownParameter(name.to_owned()));
+                            }
+                            for (name, value) in group {
+                                context.output(Parameter {
+                                    name: name.clone(),
+                                    value: value.clone(),
+                                })?;
+                            }
+                        }
+                    }
+                }
+            }
+            Subcommand::Set {
+                assignments,
+                dry_run,
+            } => {
+                let names: Vec<&str> = assignments.iter().map(|(name, _)| name.as_str()).collect();
+                let current = list(&client, &names).await?;
+                let changes = changes(&current, &assignments)?;
+
+                for change in &changes {
+                    context.output(change.clone())?;
+                }
+                if !dry_run && !changes.is_empty() {
+                    update(&client, &changes).await?;
+                }
+                context.output(Updated {
+                    changed: changes.len(),
+                    dry_run,
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    fn current() -> BTreeMap<String, String> {
+        vec![
+            ("Network.HostName", "lobby"),
+            ("Network.DNSServer1", "10.0.0.1"),
+            ("Time.NTP.Server", "pool.ntp.org"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect()
+    }
+
+    fn assignments(assignments: &[(&str, &str)]) -> Vec<(String, String)> {
+        assignments
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), (*value).to_owned()))
+            .collect()
+    }
+
+    fn summary(changes: &[Change]) -> Vec<(&str, Option<&str>, &str)> {
+        changes
+            .iter()
+            .map(|c| (c.name.as_str(), c.old.as_deref(), c.new.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn assignments_parse() {
+        assert_eq!(
+            parse_assignment("root.Network.HostName=lobby"),
+            Ok(("Network.HostName".into(), "lobby".into()))
+        );
+        assert_eq!(
+            parse_assignment("Image.Overlay=a=b"),
+            Ok(("Image.Overlay".into(), "a=b".into()))
+        );
+        assert_eq!(
+            parse_assignment("Network.HostName="),
+            Ok(("Network.HostName".into(), "".into()))
+        );
+        assert!(parse_assignment("Network.HostName").is_err());
+        assert!(parse_assignment("=lobby").is_err());
+    }
+
+    #[test]
+    fn changes_leave_out_unchanged_values() {
+        let changes = changes(
+            &current(),
+            &assignments(&[
+                ("Time.NTP.Server", "ntp.example.com"),
+                ("Network.HostName", "lobby"),
+                ("Network.DNSServer1", "10.0.0.2"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            summary(&changes),
+            vec![
+                ("Time.NTP.Server", Some("pool.ntp.org"), "ntp.example.com"),
+                ("Network.DNSServer1", Some("10.0.0.1"), "10.0.0.2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_use_the_last_assignment() {
+        let changes = changes(
+            &current(),
+            &assignments(&[
+                ("Network.HostName", "first"),
+                ("Network.DNSServer1", "10.0.0.2"),
+                ("Network.HostName", "second"),
+                ("Network.DNSServer1", "10.0.0.1"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            summary(&changes),
+            vec![("Network.HostName", Some("lobby"), "second")]
+        );
+    }
+
+    #[test]
+    fn changes_reject_unknown_parameters() {
+        let result = changes(
+            &current(),
+            &assignments(&[
+                ("Network.HostName", "reception"),
+                ("Network.HostNam", "reception"),
+            ]),
+        );
+        assert!(matches!(result, Err(Error::UnknownParameter(name)) if name == "Network.HostNam"));
+
+        // Groups aren't parameters
+        let result = changes(&current(), &assignments(&[("Network", "reception")]));
+        assert!(matches!(result, Err(Error::UnknownParameter(name)) if name == "Network"));
+    }
+
+    /// Answer parameter updates like a device whose `failing`th update fails, returning the
+    /// parameters in each request.
+    async fn device(failing: usize) -> (Context, Arc<Mutex<Vec<Vec<String>>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let server =
+            hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+                let requests = requests.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let mut requests = requests.lock().unwrap();
+                        let names = req
+                            .uri()
+                            .query()
+                            .unwrap_or_default()
+                            .split('&')
+                            .filter(|pair| *pair != "action=update")
+                            .map(|pair| pair.split('=').next().unwrap().to_owned())
+                            .collect();
+                        requests.push(names);
+                        let body = if requests.len() == failing {
+                            "# Error: Error setting parameter"
+                        } else {
+                            "OK"
+                        };
+                        let response = Response::builder()
+                            .header(http::header::CONTENT_TYPE, "text/plain")
+                            .body(Body::from(body));
+                        async move { Ok::<_, Infallible>(response.unwrap()) }
+                    }))
+                }
+            }));
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        let mut context = Context::for_test(&[], Box::new(std::io::sink()), false);
+        context.target.url = url.parse().unwrap();
+        (context, recorded)
+    }
+
+    fn many(n: usize) -> Vec<Change> {
+        (0..n)
+            .map(|i| Change {
+                name: format!("Group.P{:02}", i),
+                old: Some("old".into()),
+                new: "new".into(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn update_in_batches() {
+        let (context, requests) = device(0).await;
+        update(&context.client(), &many(70)).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let sizes: Vec<usize> = requests.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![BATCH_SIZE, BATCH_SIZE, 6]);
+        assert_eq!(requests[0][0], "Group.P00");
+        assert_eq!(requests[2][5], "Group.P69");
+    }
+
+    #[tokio::test]
+    async fn update_says_how_far_it_got() {
+        let (context, requests) = device(2).await;
+        let result = update(&context.client(), &many(70)).await;
+        assert!(matches!(result, Err(Error::PartialUpdate(32, 70, _))));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let (context, _) = device(1).await;
+        let result = update(&context.client(), &many(70)).await;
+        assert!(matches!(result, Err(Error::VapixCallFailed(_))));
+    }
+}
@@ -0,0 +1,230 @@
+use crate::cli::Context;
+use crate::output::{print_table, Output};
+use crate::profile::{self, Profiles};
+use clap::Clap;
+use crossterm::ErrorKind;
+use serde::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Manage connection profiles, which keep device credentials out of URLs
+#[derive(Debug, Clone, Clap)]
+#[clap(setting = clap::AppSettings::VersionlessSubcommands)]
+pub struct Profile {
+    #[clap(subcommand)]
+    subcommand: Subcommand,
+}
+
+#[derive(Debug, Clone, Clap)]
+enum Subcommand {
+    /// Add a profile, prompting for its password unless told where to find it
+    Add {
+        /// The name by which to refer to the device
+        name: String,
+
+        /// The device URL without credentials, i.e. http://1.2.3.4/
+        url: String,
+
+        /// The username
+        #[clap(short, long, default_value = "root")]
+        username: String,
+
+        /// Read the password from this file, which must be readable only by its owner
+        #[clap(long, parse(from_os_str), value_hint = clap::ValueHint::FilePath, conflicts_with_all = &["password-command", "ask"])]
+        password_file: Option<PathBuf>,
+
+        /// Run this shell command to obtain the password, e.g. "pass show cameras/lobby"
+        #[clap(long, conflicts_with = "ask")]
+        password_command: Option<String>,
+
+        /// Prompt for the password each time the profile is used rather than saving it
+        #[clap(long)]
+        ask: bool,
+    },
+
+    /// List profiles
+    List,
+
+    /// Remove a profile, along with any password saved for it
+    Remove {
+        /// The name of the profile
+        name: String,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error writing to terminal: {0}")]
+    TerminalError(#[from] crossterm::ErrorKind),
+    #[error("{0}")]
+    ProfileError(#[from] profile::Error),
+    #[error("error removing password file {0:?}: {1}")]
+    RemoveError(PathBuf, std::io::Error),
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ProfileInfo {
+    name: String,
+    url: String,
+    username: String,
+    password: String,
+}
+
+impl ProfileInfo {
+    fn new(name: &str, profile: &profile::Profile) -> Self {
+        Self {
+            name: name.to_owned(),
+            url: profile.url.clone(),
+            username: profile.username.clone().unwrap_or_else(|| "root".into()),
+            password: profile.password_source(),
+        }
+    }
+}
+
+impl Output for ProfileInfo {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        ProfileList(vec![self.clone()]).print(stdout)
+    }
+}
+
+/// A table of profiles.
+#[derive(Serialize)]
+struct ProfileList(Vec<ProfileInfo>);
+
+impl Output for ProfileList {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::style::Color;
+
+        let rows: Vec<[&str; 4]> = self
+            .0
+            .iter()
+            .map(|p| {
+                [
+                    p.name.as_str(),
+                    p.url.as_str(),
+                    p.username.as_str(),
+                    p.password.as_str(),
+                ]
+            })
+            .collect();
+
+        print_table(
+            stdout,
+            &["NAME", "URL", "USERNAME", "PASSWORD"],
+            &rows,
+            |_, column| match column {
+                0 => Color::Cyan,
+                _ => Color::Reset,
+            },
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct Changed {
+    name: String,
+    action: &'static str,
+}
+
+impl Output for Changed {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        queue!(
+            stdout,
+            Print(" => "),
+            Print(self.action),
+            Print(" profile "),
+            SetAttribute(Attribute::Bold),
+            Print(&self.name),
+            SetAttribute(Attribute::NormalIntensity),
+            Print("\n"),
+        )
+    }
+}
+
+/// Where `axctl profile add` saves passwords it prompts for.
+fn password_dir() -> Result<PathBuf, profile::Error> {
+    Ok(profile::config_dir()?.join("passwords"))
+}
+
+impl Profile {
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        let path = Profiles::default_path()?;
+        let mut profiles = Profiles::load(&path)?;
+
+        match self.subcommand {
+            Subcommand::Add {
+                name,
+                url,
+                username,
+                password_file,
+                password_command,
+                ask,
+            } => {
+                let mut profile = profile::Profile {
+                    url,
+                    username: Some(username),
+                    password_file,
+                    password_command,
+                };
+
+                // Check everything we can before asking for a password
+                profiles.check_new(&name, &profile)?;
+
+                if profile.password_file.is_none() && profile.password_command.is_none() && !ask {
+                    let password = profile::prompt_password(&format!("Password for {}: ", name))
+                        .map_err(profile::Error::PromptError)?;
+                    let password_file = password_dir()?.join(&name);
+                    profile::write_password_file(&password_file, &password)?;
+                    profile.password_file = Some(password_file);
+                }
+
+                profiles.insert(&name, profile)?;
+                profiles.save(&path)?;
+                context.output(Changed {
+                    name,
+                    action: "added",
+                })?;
+            }
+            Subcommand::List => {
+                let list: Vec<ProfileInfo> = profiles
+                    .iter()
+                    .map(|(name, profile)| ProfileInfo::new(name, profile))
+                    .collect();
+
+                if context.is_tty {
+                    context.output(ProfileList(list))?;
+                } else {
+                    for profile in list {
+                        context.output(profile)?;
+                    }
+                }
+            }
+            Subcommand::Remove { name } => {
+                let profile = profiles.remove(&name)?;
+                profiles.save(&path)?;
+
+                // Only remove passwords we saved ourselves
+                if let Some(password_file) = profile.password_file {
+                    if password_file.parent() == Some(password_dir()?.as_path()) {
+                        std::fs::remove_file(&password_file)
+                            .or_else(|e| match e.kind() {
+                                std::io::ErrorKind::NotFound => Ok(()),
+                                _ => Err(e),
+                            })
+                            .map_err(|e| Error::RemoveError(password_file, e))?;
+                    }
+                }
+
+                context.output(Changed {
+                    name,
+                    action: "removed",
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
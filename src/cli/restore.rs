@@ -0,0 +1,749 @@
+use super::app;
+use super::backup::{
+    self, count, Manifest, APPLICATIONS, FIRMWARE_VERSION, MANIFEST, MODEL, PACKAGES, PARAMETERS,
+};
+use super::cp;
+use super::exec::quote;
+use super::param::{self, Change};
+use super::shell::{self, end_session, start_session, Session};
+use crate::cli::{Context, Transport};
+use crate::eap::Package;
+use crate::output::{Level, Output};
+use clap::Clap;
+use crossterm::terminal::{Clear, ClearType};
+use crossterm::ErrorKind;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use vapix::v3::application::{Application, ApplicationStatus, ControlAction};
+
+/// How long to wait for an application to start or stop.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parameter groups which describe the device rather than how it's configured, and which the device
+/// won't let anyone change.
+const READ_ONLY: &[&str] = &[
+    "Brand",
+    "Properties",
+    "HardwareConfig",
+    "Network.Interface.I0.Active",
+    "Network.eth0.MACAddress",
+];
+
+/// Restore a backup from `axctl backup`, i.e. onto a replacement device of the same model
+#[derive(Debug, Clone, Clap)]
+pub struct Restore {
+    /// The backup to restore
+    #[clap(parse(from_os_str), value_hint = clap::ValueHint::FilePath)]
+    backup: PathBuf,
+
+    /// An application package to install if the backup has the application but the device doesn't
+    /// (may be repeated)
+    #[clap(long = "package", number_of_values = 1, parse(from_os_str))]
+    packages: Vec<PathBuf>,
+
+    /// Leave a parameter group as it is, i.e. `Network` to keep the device's address (may be
+    /// repeated)
+    #[clap(long, number_of_values = 1)]
+    skip: Vec<String>,
+
+    /// Leave out files, which avoids starting a shell on the device
+    #[clap(long)]
+    no_files: bool,
+
+    /// Show what would change without changing it
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// The remote port on which to establish a shell-over-SSL connection
+    #[clap(short, long)]
+    port: Option<u16>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error writing to terminal: {0}")]
+    TerminalError(#[from] crossterm::ErrorKind),
+    #[error("VAPIX call failed: {0}")]
+    VapixCallFailed(#[from] vapix::Error),
+    #[error("{0}")]
+    ShellError(#[from] shell::Error),
+    #[error("{0}")]
+    CpError(#[from] cp::Error),
+    #[error("{0}")]
+    AppError(#[from] app::Error),
+    #[error("{0}")]
+    ParamError(#[from] param::Error),
+    #[error("error reading {0:?}: {1}")]
+    ReadError(PathBuf, std::io::Error),
+    #[error("{0:?} is not a backup from `axctl backup`: {1}")]
+    NotABackup(PathBuf, std::io::Error),
+    #[error("{0:?} is not a backup from `axctl backup`, since it has no {1}")]
+    MissingEntry(PathBuf, String),
+    #[error("error reading {1} from {0:?}: {2}")]
+    InvalidEntry(PathBuf, String, serde_json::Error),
+    #[error("{0:?} is not a usable application package: {1}")]
+    PackageError(PathBuf, crate::eap::Error),
+    #[error("{0:?} does not say which application it contains")]
+    UnnamedPackage(PathBuf),
+}
+
+/// The contents of a backup.
+struct Contents {
+    manifest: Manifest,
+    parameters: BTreeMap<String, String>,
+    applications: Vec<Application>,
+    /// Archives of files and directories, by their path on the device
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Contents {
+    fn read(path: &Path) -> Result<Self, Error> {
+        let bytes = std::fs::read(path).map_err(|e| Error::ReadError(path.into(), e))?;
+        let not_a_backup = |e| Error::NotABackup(path.into(), e);
+
+        let mut entries: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        let mut archive = ::tar::Archive::new(flate2::read::GzDecoder::new(bytes.as_slice()));
+        for entry in archive.entries().map_err(not_a_backup)? {
+            let mut entry = entry.map_err(not_a_backup)?;
+            let name = entry
+                .path()
+                .map_err(not_a_backup)?
+                .to_string_lossy()
+                .into_owned();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).map_err(not_a_backup)?;
+            entries.insert(name, data);
+        }
+
+        let mut take = |name: &str| {
+            entries
+                .remove(name)
+                .ok_or_else(|| Error::MissingEntry(path.into(), name.to_owned()))
+        };
+        fn parse<T: DeserializeOwned>(path: &Path, name: &str, data: &[u8]) -> Result<T, Error> {
+            serde_json::from_slice(data)
+                .map_err(|e| Error::InvalidEntry(path.into(), name.to_owned(), e))
+        }
+
+        let manifest: Manifest = parse(path, MANIFEST, &take(MANIFEST)?)?;
+        let parameters = parse(path, PARAMETERS, &take(PARAMETERS)?)?;
+        let applications = parse(path, APPLICATIONS, &take(APPLICATIONS)?)?;
+        let files = manifest
+            .files
+            .iter()
+            .map(|file| Ok((file.clone(), take(&backup::entry_for(file))?)))
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            manifest,
+            parameters,
+            applications,
+            files,
+        })
+    }
+}
+
+/// Whether `name` is the parameter or group `group`, or is in it.
+fn in_group(name: &str, group: &str) -> bool {
+    name.strip_prefix(group)
+        .map(|rest| rest.is_empty() || rest.starts_with('.'))
+        .unwrap_or(false)
+}
+
+/// The application whose directory a file is in, if any.
+fn application_of(path: &str) -> Option<&str> {
+    path.strip_prefix(PACKAGES)?
+        .strip_prefix('/')?
+        .split('/')
+        .next()
+}
+
+#[derive(Serialize)]
+#[serde(tag = "warning", rename_all = "snake_case")]
+enum Warning {
+    /// The backup came from a device with other hardware or firmware
+    Mismatch {
+        property: &'static str,
+        backup: String,
+        device: String,
+    },
+    /// The backup has an application which the device doesn't
+    MissingApplication { name: String, version: String },
+    /// The device wouldn't take a parameter's value from the backup
+    Rejected { name: String, error: String },
+}
+
+impl Output for Warning {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        let message = match self {
+            Warning::Mismatch {
+                property,
+                backup,
+                device,
+            } => format!(
+                "the backup was made on {} {}, but this device has {}",
+                property, backup, device
+            ),
+            Warning::MissingApplication { name, version } => format!(
+                "{} {} is not installed, so its parameters and files will be skipped; \
+                 use --package to install it",
+                name, version
+            ),
+            Warning::Rejected { name, error } => {
+                format!("the device rejected a new value for {}: {}", name, error)
+            }
+        };
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Yellow),
+            Print(" => warning: "),
+            ResetColor,
+            Print(message),
+            Print("\n"),
+        )
+    }
+
+    fn level(&self) -> Level {
+        Level::Error
+    }
+}
+
+#[derive(Serialize)]
+struct RestoredFile {
+    path: String,
+    bytes: usize,
+}
+
+impl Output for RestoredFile {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        crossterm::queue!(
+            stdout,
+            Clear(ClearType::CurrentLine),
+            crossterm::style::Print(format!(
+                "\r => restored device:{} ({} bytes)\n",
+                self.path, self.bytes
+            )),
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct Restored {
+    parameters: usize,
+    files: usize,
+    applications: usize,
+    /// Parameters from the backup which the device doesn't have
+    skipped: usize,
+    /// Parameters the device wouldn't change
+    rejected: usize,
+    dry_run: bool,
+}
+
+impl Output for Restored {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), ErrorKind> {
+        use crossterm::{queue, style::*};
+
+        let verb = if self.dry_run {
+            "would restore"
+        } else {
+            "restored"
+        };
+        queue!(
+            stdout,
+            SetForegroundColor(Color::Green),
+            Print(format!(
+                " => {} {}, {} and {}",
+                verb,
+                count(self.parameters, "parameter"),
+                count(self.files, "file"),
+                count(self.applications, "application state"),
+            )),
+            ResetColor,
+        )?;
+        if self.skipped > 0 {
+            queue!(
+                stdout,
+                Print(format!(
+                    "; skipped {} the device doesn't have",
+                    count(self.skipped, "parameter")
+                ))
+            )?;
+        }
+        if self.rejected > 0 {
+            queue!(
+                stdout,
+                Print(format!(
+                    "; the device rejected {}",
+                    count(self.rejected, "parameter")
+                ))
+            )?;
+        }
+        queue!(stdout, Print("\n"))
+    }
+}
+
+/// Find the application in each package, so that packages can be given in any order.
+fn read_packages(paths: &[PathBuf]) -> Result<BTreeMap<String, PathBuf>, Error> {
+    let mut packages = BTreeMap::new();
+    for path in paths {
+        let bytes = std::fs::read(path).map_err(|e| Error::ReadError(path.clone(), e))?;
+        let package = Package::parse(&bytes).map_err(|e| Error::PackageError(path.clone(), e))?;
+        let name = package
+            .name()
+            .ok_or_else(|| Error::UnnamedPackage(path.clone()))?;
+        packages.insert(name.to_owned(), path.clone());
+    }
+    Ok(packages)
+}
+
+/// Start or stop installed applications so that they're as they were in the backup.
+fn controls(backed_up: &[Application], installed: &[Application]) -> Vec<(String, ControlAction)> {
+    backed_up
+        .iter()
+        .filter_map(|application| {
+            let now = installed.iter().find(|a| a.name == application.name)?;
+            let action = match (application.status, now.status) {
+                (ApplicationStatus::Running, ApplicationStatus::Running) => return None,
+                (ApplicationStatus::Running, _) => ControlAction::Start,
+                (ApplicationStatus::Stopped, ApplicationStatus::Running) => ControlAction::Stop,
+                _ => return None,
+            };
+            Some((application.name.clone(), action))
+        })
+        .collect()
+}
+
+/// Work out which backed up parameters differ from the device's, leaving out those which are
+/// read-only or in `skip` groups, and putting network settings last. Also returns how many the
+/// device doesn't have.
+fn differences(
+    backed_up: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+    skip: &[String],
+) -> (Vec<Change>, usize) {
+    let mut skipped = 0;
+    let mut changes = Vec::new();
+    let skip = |name: &str| {
+        READ_ONLY
+            .iter()
+            .copied()
+            .chain(skip.iter().map(String::as_str))
+            .any(|group| in_group(name, group))
+    };
+    for (name, value) in backed_up {
+        if skip(name) {
+            continue;
+        }
+        match current.get(name) {
+            Some(old) if old != value => changes.push(Change {
+                name: name.clone(),
+                old: Some(old.clone()),
+                new: value.clone(),
+            }),
+            Some(_) => {}
+            None => skipped += 1,
+        }
+    }
+    changes.sort_by_key(|change| in_group(&change.name, "Network"));
+    (changes, skipped)
+}
+
+/// Change parameters in batches like `param::update()`, except that when the device rejects a
+/// batch, its parameters are changed one at a time and the ones it rejects are skipped. Returns
+/// how many were rejected.
+async fn update(
+    context: &mut Context,
+    client: &vapix::Client<Transport>,
+    changes: &[Change],
+) -> Result<usize, Error> {
+    let parameters = client.parameters();
+    let mut rejected = 0;
+    for batch in changes.chunks(param::BATCH_SIZE) {
+        match parameters
+            .update(batch.iter().map(|c| (&c.name, &c.new)))
+            .await
+        {
+            Ok(()) => continue,
+            // Nothing else is going to get through either
+            Err(e @ vapix::Error::TransportError(_)) => return Err(e.into()),
+            Err(_) => {}
+        }
+        for change in batch {
+            match parameters
+                .update(std::iter::once((&change.name, &change.new)))
+                .await
+            {
+                Ok(()) => {}
+                Err(e @ vapix::Error::TransportError(_)) => return Err(e.into()),
+                Err(e) => {
+                    rejected += 1;
+                    context.output(Warning::Rejected {
+                        name: change.name.clone(),
+                        error: e.to_string(),
+                    })?;
+                }
+            }
+        }
+    }
+    Ok(rejected)
+}
+
+impl Restore {
+    /// Put a file or directory from the backup in place, replacing whatever is there.
+    async fn restore_file(
+        context: &mut Context,
+        conn: &mut shell::Connection,
+        path: &str,
+        archive: &[u8],
+    ) -> Result<(), Error> {
+        let name = path
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or(path);
+        let (workdir, _) = cp::send(context, conn, archive, format!("device:{}", path)).await?;
+        cp::remote(
+            conn,
+            &format!(
+                r#"workdir={} name={} dest={}
+mkdir "$workdir/stage" &&
+  tar -xzf "$workdir/archive.tar.gz" -C "$workdir/stage" &&
+  mkdir -p "`dirname "$dest"`" &&
+  rm -rf "$dest" &&
+  mv -f "$workdir/stage/$name" "$dest"
+status=$?
+rm -rf "$workdir"
+exit $status"#,
+                quote(&workdir),
+                quote(name),
+                quote(path),
+            ),
+        )
+        .await?;
+
+        context.output(RestoredFile {
+            path: path.to_owned(),
+            bytes: archive.len(),
+        })?;
+        Ok(())
+    }
+
+    pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
+        let backup = Contents::read(&self.backup)?;
+        let packages = read_packages(&self.packages)?;
+
+        let client = context.client();
+        let current = param::list(&client, &[MODEL, FIRMWARE_VERSION]).await?;
+
+        // Restoring onto different hardware or firmware may well work, but it's worth knowing
+        let comparisons = [
+            ("model", &backup.manifest.model, current.get(MODEL)),
+            (
+                "firmware",
+                &backup.manifest.firmware_version,
+                current.get(FIRMWARE_VERSION),
+            ),
+        ];
+        for (property, backup, device) in comparisons.iter() {
+            if let (Some(backup), Some(device)) = (backup, device) {
+                if backup != *device {
+                    context.output(Warning::Mismatch {
+                        property,
+                        backup: backup.clone(),
+                        device: (*device).clone(),
+                    })?;
+                }
+            }
+        }
+
+        let applications = if backup.applications.is_empty() {
+            None
+        } else {
+            client.applications().await?
+        };
+        let mut installed = match &applications {
+            Some(applications) => applications.list().await?,
+            None => Vec::new(),
+        };
+
+        // Install what's missing from the packages we were given, then warn about the rest
+        for application in &backup.applications {
+            if installed.iter().any(|a| a.name == application.name) {
+                continue;
+            }
+            match (&applications, packages.get(&application.name)) {
+                (Some(applications), Some(package)) if !self.dry_run => {
+                    app::install(context, applications, package.clone(), false).await?;
+                }
+                (Some(_), Some(_)) => {}
+                _ => {
+                    context.output(Warning::MissingApplication {
+                        name: application.name.clone(),
+                        version: application.version.clone(),
+                    })?;
+                }
+            }
+        }
+        if let Some(applications) = &applications {
+            if !self.dry_run {
+                installed = applications.list().await?;
+            }
+        }
+        let is_installed = |name: &str| installed.iter().any(|a| a.name == name);
+
+        // Work out which parameters differ, putting network settings last
+        let current = param::list(&client, &[] as &[&str]).await?;
+        let (changes, skipped) = differences(&backup.parameters, &current, &self.skip);
+        for change in &changes {
+            context.output(change.clone())?;
+        }
+
+        let files: Vec<&(String, Vec<u8>)> = if self.no_files {
+            Vec::new()
+        } else {
+            backup
+                .files
+                .iter()
+                .filter(|(path, _)| application_of(path).map(is_installed).unwrap_or(true))
+                .collect()
+        };
+
+        if self.dry_run {
+            context.output(Restored {
+                parameters: changes.len(),
+                files: files.len(),
+                applications: controls(&backup.applications, &installed).len(),
+                skipped,
+                rejected: 0,
+                dry_run: true,
+            })?;
+            return Ok(());
+        }
+
+        // Network settings are changed separately and last of all, since changing them might well
+        // cut us off
+        let network = changes
+            .iter()
+            .position(|change| in_group(&change.name, "Network"))
+            .unwrap_or(changes.len());
+        let (others, network) = changes.split_at(network);
+
+        // Applications shouldn't have their files replaced while they're running, so stop them,
+        // and whatever happens, start them again before going any further
+        let mut stopped = Vec::new();
+        let result = async {
+            if !files.is_empty() {
+                let applications = applications
+                    .as_ref()
+                    .ok_or(shell::Error::DeviceDoesNotSupportApplicationUploads)?;
+
+                for application in &installed {
+                    let has_files = files
+                        .iter()
+                        .any(|(path, _)| application_of(path) == Some(&application.name));
+                    if has_files && application.status == ApplicationStatus::Running {
+                        app::control(
+                            context,
+                            applications,
+                            ControlAction::Stop,
+                            application.name.clone(),
+                            Some(CONTROL_TIMEOUT),
+                        )
+                        .await?;
+                        stopped.push(application.name.clone());
+                    }
+                }
+
+                let Session { id, mut conn, .. } =
+                    start_session(context, applications, self.port, false).await?;
+                let mut result = Ok(());
+                for (path, archive) in &files {
+                    result = Self::restore_file(context, &mut conn, path, archive).await;
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                std::mem::drop(conn);
+
+                end_session(context, applications, id).await?;
+                result?;
+            }
+
+            update(context, &client, others).await
+        }
+        .await;
+
+        let rejected = match result {
+            Ok(rejected) => rejected,
+            Err(e) => {
+                // Put back what we stopped, but it's the original error that matters
+                if let Some(applications) = &applications {
+                    for name in stopped {
+                        let _ = app::control(
+                            context,
+                            applications,
+                            ControlAction::Start,
+                            name,
+                            Some(CONTROL_TIMEOUT),
+                        )
+                        .await;
+                    }
+                }
+                return Err(e);
+            }
+        };
+
+        // Start or stop applications as they were in the backup, which also starts the ones
+        // stopped above if they were running then
+        let mut controls = Vec::new();
+        if let Some(applications) = &applications {
+            installed = applications.list().await?;
+            controls = self::controls(&backup.applications, &installed);
+            for (name, action) in &controls {
+                app::control(
+                    context,
+                    applications,
+                    *action,
+                    name.clone(),
+                    Some(CONTROL_TIMEOUT),
+                )
+                .await?;
+            }
+        }
+
+        let rejected = rejected + update(context, &client, network).await?;
+
+        context.output(Restored {
+            parameters: changes.len() - rejected,
+            files: files.len(),
+            applications: controls.len(),
+            skipped,
+            rejected,
+            dry_run: false,
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vapix::v3::application::LicenseStatus;
+
+    #[test]
+    fn groups() {
+        assert!(in_group("Network", "Network"));
+        assert!(in_group("Network.HostName", "Network"));
+        assert!(in_group("Network.eth0.MACAddress", "Network.eth0"));
+        assert!(!in_group("NetworkX.Y", "Network"));
+        assert!(!in_group("Network", "Network.HostName"));
+        assert!(!in_group("Image.Network", "Network"));
+    }
+
+    fn parameters(parameters: &[(&str, &str)]) -> BTreeMap<String, String> {
+        parameters
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), (*value).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn differences_leave_out_read_only_and_skipped_groups() {
+        let backed_up = parameters(&[
+            ("Brand.ProdNbr", "M3106-L"),
+            ("Network.eth0.MACAddress", "00:40:8C:00:00:01"),
+            ("Network.HostName", "lobby"),
+            ("Image.I0.Stream.FPS", "15"),
+            ("PTZ.Preset.P0.Name", "door"),
+            ("Time.NTP.Server", "pool.ntp.org"),
+        ]);
+        let current = parameters(&[
+            ("Brand.ProdNbr", "M3106-LVE"),
+            ("Network.eth0.MACAddress", "00:40:8C:00:00:02"),
+            ("Network.HostName", "axis-00408c000002"),
+            ("Image.I0.Stream.FPS", "30"),
+            ("Time.NTP.Server", "pool.ntp.org"),
+        ]);
+
+        let (changes, skipped) = differences(&backed_up, &current, &[]);
+        let names: Vec<&str> = changes.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Image.I0.Stream.FPS", "Network.HostName"]);
+        assert_eq!(changes[0].old.as_deref(), Some("30"));
+        assert_eq!(changes[0].new, "15");
+        // The device has no PTZ
+        assert_eq!(skipped, 1);
+
+        let (changes, skipped) = differences(&backed_up, &current, &["Network".into()]);
+        let names: Vec<&str> = changes.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Image.I0.Stream.FPS"]);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn network_changes_come_last() {
+        let backed_up = parameters(&[
+            ("Audio.A0.Enabled", "yes"),
+            ("Network.HostName", "lobby"),
+            ("NetworkX.Y", "z"),
+        ]);
+        let current = parameters(&[
+            ("Audio.A0.Enabled", "no"),
+            ("Network.HostName", "axis"),
+            ("NetworkX.Y", "y"),
+        ]);
+        let (changes, _) = differences(&backed_up, &current, &[]);
+        let names: Vec<&str> = changes.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["Audio.A0.Enabled", "NetworkX.Y", "Network.HostName"]
+        );
+    }
+
+    fn application(name: &str, status: ApplicationStatus) -> Application {
+        Application {
+            name: name.into(),
+            nice_name: name.into(),
+            vendor: "Axis Communications".into(),
+            version: "1.0".into(),
+            application_id: None,
+            license: LicenseStatus::None,
+            status,
+            configuration_page: None,
+            vendor_home_page: None,
+        }
+    }
+
+    #[test]
+    fn controls_restore_states() {
+        use ApplicationStatus::{Running, Stopped};
+
+        let backed_up = [
+            application("vmd", Running),
+            application("fence", Stopped),
+            application("counter", Running),
+            application("loiter", Stopped),
+            application("missing", Running),
+        ];
+        let installed = [
+            application("vmd", Stopped),
+            application("fence", Running),
+            application("counter", Running),
+            application("loiter", Stopped),
+            application("extra", Running),
+        ];
+        assert_eq!(
+            controls(&backed_up, &installed),
+            vec![
+                ("vmd".to_owned(), ControlAction::Start),
+                ("fence".to_owned(), ControlAction::Stop),
+            ]
+        );
+    }
+}
@@ -1,29 +1,40 @@
 use self::end_package::EndPackage;
-use self::start_package::StartPackage;
-use crate::cli::Context;
+use self::start_package::{Pty, StartPackage};
+use self::terminal::{encode_key, RawMode};
+use crate::cli::{Context, Transport};
 use crate::mutual_tls;
 use crate::output::{Level, Output};
 use clap::Clap;
+use crossterm::event::{Event, EventStream};
+use crossterm::tty::IsTty;
 use crossterm::{queue, style::Print};
 use futures::future::Either;
 use futures::{pin_mut, prelude::*, select_biased};
 use serde::Serialize;
-use std::io::{Read, Stdout, Write};
+use std::io::{Read, Write};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::Duration;
 use thiserror::Error;
 use tokio::prelude::*;
 use uuid::Uuid;
+use vapix::v3::Applications;
 
 mod end_package;
 mod start_package;
+mod terminal;
 
 /// Run an interactive shell on an AXIS camera
-#[derive(Debug, Clap)]
+#[derive(Debug, Clone, Clap)]
 pub struct Shell {
     /// The remote port on which to establish a shell-over-SSL connection
+    ///
+    /// The following port is also used to forward window size changes.
     #[clap(short, long)]
     port: Option<u16>,
+
+    /// Disable pseudo-terminal allocation
+    #[clap(short = 'T', long)]
+    no_pty: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -34,7 +45,7 @@ struct StartMessage {
 }
 
 impl Output for StartMessage {
-    fn print(&self, stdout: &mut Stdout) -> Result<(), crossterm::ErrorKind> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
         queue!(
             stdout,
             Print(format!(
@@ -58,7 +69,7 @@ struct ConnectedMessage {
 }
 
 impl Output for ConnectedMessage {
-    fn print(&self, stdout: &mut Stdout) -> Result<(), crossterm::ErrorKind> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
         queue!(
             stdout,
             Print(format!(
@@ -82,7 +93,7 @@ struct NegotiatedMessage<'a> {
 }
 
 impl<'a> Output for NegotiatedMessage<'a> {
-    fn print(&self, stdout: &mut Stdout) -> Result<(), crossterm::ErrorKind> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
         queue!(
             stdout,
             Print(format!(
@@ -100,6 +111,30 @@ impl<'a> Output for NegotiatedMessage<'a> {
     }
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PtyUnavailableMessage {
+    resize_addr: SocketAddr,
+    error: String,
+}
+
+impl Output for PtyUnavailableMessage {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
+        queue!(
+            stdout,
+            Print(format!(
+                " => continuing without a pseudo-terminal: {}: {}\n",
+                &self.resize_addr, &self.error
+            ))
+        )?;
+        Ok(())
+    }
+
+    fn level(&self) -> Level {
+        Level::Info
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct CleaningUpMessage {
@@ -107,7 +142,7 @@ struct CleaningUpMessage {
 }
 
 impl Output for CleaningUpMessage {
-    fn print(&self, stdout: &mut Stdout) -> Result<(), crossterm::ErrorKind> {
+    fn print(&self, stdout: &mut dyn Write) -> Result<(), crossterm::ErrorKind> {
         queue!(
             stdout,
             Print(format!("\n => cleaning up session {}\n", &self.id))
@@ -205,32 +240,193 @@ pub enum Error {
     ConnectionClosed(std::io::Error),
 }
 
+/// A connection to a shell running on the device.
+pub(super) type Connection = tokio_openssl::SslStream<tokio::net::TcpStream>;
+
+/// A shell session started by `start_session()`.
+pub(super) struct Session {
+    pub id: Uuid,
+    pub conn: Connection,
+    /// A connection over which to send window size changes, which is only there if the shell has
+    /// a pseudo-terminal
+    pub resize: Option<Connection>,
+}
+
+/// Start a shell on the device by uploading a `StartPackage`, then connect to it via mutual TLS.
+///
+/// If `pty` is set, the shell runs on a pseudo-terminal matching ours, if the device can give it
+/// one.
+pub(super) async fn start_session(
+    context: &mut Context,
+    applications: &Applications<'_, Transport>,
+    port: Option<u16>,
+    pty: bool,
+) -> Result<Session, Error> {
+    // Pick an ID
+    let id = Uuid::new_v4();
+
+    // Make some secrets
+    let mutual_tls::Pair { client, server } =
+        mutual_tls::Pair::new(&format!("trunnion shell {}", &id));
+    let client_connector = client.ssl_client_connector();
+
+    // Pick a port, plus the next one for window size changes
+    let port = port.unwrap_or_else(|| choose_port());
+    let resize_port = port.checked_add(1).unwrap_or(port - 1);
+
+    // Resolve the hostname
+    let hostname = context
+        .target
+        .url
+        .host()
+        .expect("URL must have a host component")
+        .to_owned();
+    let shell_addr = (hostname.as_ref(), port)
+        .to_socket_addrs()
+        .map_err(|e| Error::HostnameResolutionError(hostname.clone(), e))?
+        .next()
+        .expect("name resolution produced no addresses");
+    let resize_addr = SocketAddr::new(shell_addr.ip(), resize_port);
+
+    // Indicate we're about to start
+    context.output(StartMessage { id, shell_addr })?;
+
+    // Ensure we promptly get a connection refused on the target port(s)
+    ensure_closed(shell_addr, Duration::from_secs(2))
+        .await
+        .map_err(|e| Error::ProbeError(shell_addr, e))?;
+    if pty {
+        ensure_closed(resize_addr, Duration::from_secs(2))
+            .await
+            .map_err(|e| Error::ProbeError(resize_addr, e))?;
+    }
+
+    let conn = {
+        // Upload our start package to the camera
+        let pty = if pty {
+            Some(Pty {
+                term: terminal_type(),
+                resize_port,
+            })
+        } else {
+            None
+        };
+        let eap = StartPackage::new(id, port, pty, &server).into_eap();
+        let upload_start = async {
+            // upload the package
+            let result = applications.upload(&eap).await;
+
+            // wait 5 seconds after upload completed for the shell to start
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+
+            // now return the result
+            result
+        }
+        .fuse();
+        pin_mut!(upload_start);
+
+        // Repeatedly dial the shell port for the next while
+        let dial = dial(shell_addr, Duration::from_secs(20)).fuse();
+        pin_mut!(dial);
+
+        select_biased! {
+            conn = dial => {
+                // We're connected! Or we failed.
+                conn.map_err(Error::ShellConnectionError)
+            }
+            _ = upload_start => {
+                // If we haven't connected but the upload is completed (and done waiting), then
+                // consider this a failed-to-start situation
+                return Err(Error::ShellFailedToStart);
+            }
+        }
+    }?;
+
+    context.output(ConnectedMessage { id, shell_addr })?;
+
+    // Negotiate TLS
+    let conn = tls_connect(&client_connector, &hostname, conn)
+        .await
+        .map_err(Error::TlsHandshakeFailed)?;
+
+    context.output(NegotiatedMessage {
+        tls_version: conn.ssl().version_str(),
+        cipher: conn.ssl().current_cipher().map(|cipher| cipher.name()),
+    })?;
+
+    // Connect the resize channel, which is listening by now unless the device couldn't give the
+    // shell a pseudo-terminal
+    let resize = if pty {
+        let resize = async {
+            let conn = dial(resize_addr, Duration::from_secs(2))
+                .await
+                .map_err(|e| e.to_string())?;
+            tls_connect(&client_connector, &hostname, conn).await
+        };
+        match resize.await {
+            Ok(resize) => Some(resize),
+            Err(error) => {
+                context.output(PtyUnavailableMessage { resize_addr, error })?;
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(Session { id, conn, resize })
+}
+
+async fn tls_connect(
+    connector: &openssl::ssl::SslConnector,
+    hostname: &str,
+    conn: tokio::net::TcpStream,
+) -> Result<Connection, String> {
+    // Get a connect config with all the certificates configured
+    let mut config = connector
+        .configure()
+        .expect("error getting TLS client config");
+
+    // Don't insist that the CN of the certificate matches our expectations
+    config.set_verify_hostname(false);
+
+    // Do the handshake
+    tokio_openssl::connect(config, hostname, conn)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// The terminal type to request on the device, defaulting to something widely understood.
+fn terminal_type() -> String {
+    std::env::var("TERM")
+        .ok()
+        .filter(|term| {
+            !term.is_empty()
+                && term
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c))
+        })
+        .unwrap_or_else(|| "xterm".into())
+}
+
+/// Clean up after a session started by `start_session()`.
+///
+/// This is best-effort: if the device can't be reached, there's nothing else we can do.
+pub(super) async fn end_session(
+    context: &mut Context,
+    applications: &Applications<'_, Transport>,
+    id: Uuid,
+) -> Result<(), Error> {
+    context.output(CleaningUpMessage { id })?;
+
+    let eap = EndPackage::new(id).into_eap();
+    let _ = applications.upload(&eap).await;
+
+    Ok(())
+}
+
 impl Shell {
     pub async fn invoke(self, context: &mut Context) -> Result<(), Error> {
-        // Pick an ID
-        let id = Uuid::new_v4();
-
-        // Make some secrets
-        let mutual_tls::Pair { client, server } =
-            mutual_tls::Pair::new(&format!("trunnion shell {}", &id));
-        let client_connector = client.ssl_client_connector();
-
-        // Pick a port
-        let port = self.port.unwrap_or_else(|| choose_port());
-
-        // Resolve the hostname
-        let hostname = context
-            .global_options
-            .device_url
-            .host()
-            .expect("URL must have a host component")
-            .to_owned();
-        let shell_addr = (hostname.as_ref(), port)
-            .to_socket_addrs()
-            .map_err(|e| Error::HostnameResolutionError(hostname.clone(), e))?
-            .next()
-            .expect("name resolution produced no addresses");
-
         // Get the VAPIX applications interface
         // (This also verifies our connectivity, our credentials, etc.)
         let client = context.client();
@@ -240,135 +436,143 @@ impl Shell {
             .map_err(Error::VapixError)?
             .ok_or(Error::DeviceDoesNotSupportApplicationUploads)?;
 
-        // Indicate we're about to start
-        context.output(StartMessage { id, shell_addr })?;
-
-        // Ensure we promptly get a connection refused on the target port
-        ensure_closed(shell_addr, Duration::from_secs(2))
-            .await
-            .map_err(|e| Error::ProbeError(shell_addr, e))?;
+        // Like ssh, only allocate a pseudo-terminal when we're attached to a terminal ourselves
+        let pty = !self.no_pty && context.is_tty && context.stdin.is_tty();
 
-        let conn = {
-            // Upload our start package to the camera
-            let eap = StartPackage::new(id, port, &server).into_eap();
-            let upload_start = async {
-                // upload the package
-                let result = applications.upload(&eap).await;
+        let session = start_session(context, &applications, self.port, pty).await?;
+        let id = session.id;
 
-                // wait 5 seconds after upload completed for the shell to start
-                tokio::time::delay_for(Duration::from_secs(5)).await;
+        let result = match session.resize {
+            Some(resize) => interactive(session.conn, resize).await,
+            None => piped(session.conn).await,
+        };
 
-                // now return the result
-                result
-            }
-            .fuse();
-            pin_mut!(upload_start);
+        end_session(context, &applications, id).await?;
 
-            // Repeatedly dial the shell port for the next while
-            let dial = dial(shell_addr, Duration::from_secs(20)).fuse();
-            pin_mut!(dial);
+        return result;
+    }
+}
 
-            select_biased! {
-                conn = dial => {
-                    // We're connected! Or we failed.
-                    conn.map_err(Error::ShellConnectionError)
-                }
-                _ = upload_start => {
-                    // If we haven't connected but the upload is completed (and done waiting), then
-                    // consider this a failed-to-start situation
-                    return Err(Error::ShellFailedToStart);
+/// Connect the shell to our terminal, which is put into raw mode for the duration.
+async fn interactive(conn: Connection, resize: Connection) -> Result<(), Error> {
+    let _raw_mode = RawMode::enable()?;
+
+    // Split into read and write halves
+    let (conn_read, mut conn_write) = tokio::io::split(conn);
+
+    let c2s = async move {
+        let mut resize = Some(resize);
+        let mut events = EventStream::new();
+
+        // Tell the device how big we are, then keep it up to date
+        let (mut cols, mut rows) = crossterm::terminal::size()?;
+        loop {
+            if let Some(conn) = resize.as_mut() {
+                let size = format!("{} {}\n", rows, cols);
+                let sent = async {
+                    conn.write_all(size.as_bytes()).await?;
+                    conn.flush().await
+                };
+                if sent.await.is_err() {
+                    // The shell works without it
+                    resize = None;
                 }
             }
-        }?;
 
-        context.output(ConnectedMessage { id, shell_addr })?;
-
-        // Negotiate TLS
-        let conn = {
-            // Get a connect config with all the certificates configured
-            let mut config = client_connector
-                .configure()
-                .expect("error getting TLS client config");
+            loop {
+                let bytes = match events.next().await {
+                    Some(Ok(Event::Key(key))) => encode_key(key),
+                    Some(Ok(Event::Resize(new_cols, new_rows))) => {
+                        cols = new_cols;
+                        rows = new_rows;
+                        break;
+                    }
+                    Some(Ok(Event::Mouse(_))) => continue,
+                    // crossterm reports input it can't parse as an error, which isn't fatal
+                    Some(Err(_)) => continue,
+                    None => return Ok(()),
+                };
+
+                conn_write
+                    .write_all(&bytes)
+                    .await
+                    .map_err(Error::ConnectionClosed)?;
+                conn_write.flush().await.map_err(Error::ConnectionClosed)?;
+            }
+        }
+    };
 
-            // Don't insist that the CN of the certificate matches our expectations
-            config.set_verify_hostname(false);
+    let s2c = copy_to_stdout(conn_read);
 
-            // Do the handshake
-            tokio_openssl::connect(config, &hostname, conn)
-                .await
-                .map_err(|e| Error::TlsHandshakeFailed(e.to_string()))?
-        };
+    // Wait until either c2s ends normally or s2c hits an error
+    pin_mut!(s2c);
+    pin_mut!(c2s);
+    match future::try_select(c2s, s2c).await {
+        Ok(_) => Ok(()),
+        Err(Either::Left((e, _))) | Err(Either::Right((e, _))) => Err(e),
+    }
+}
 
-        context.output(NegotiatedMessage {
-            tls_version: conn.ssl().version_str(),
-            cipher: conn.ssl().current_cipher().map(|cipher| cipher.name()),
-        })?;
-
-        // Split into read and write halves
-        let (mut conn_read, mut conn_write) = tokio::io::split(conn);
-
-        let c2s = async move {
-            tokio::task::spawn(async move {
-                let mut buf = [0u8; 1024];
-                let mut stdin = std::io::stdin();
-                loop {
-                    match tokio::task::block_in_place(|| stdin.read(&mut buf)) {
-                        Ok(0) => break Ok(()),
-                        Ok(n) => {
-                            match conn_write.write_all(&buf[0..n]).await {
-                                Err(e) => break Err(Error::ConnectionClosed(e)),
-                                _ => {}
-                            };
-                            match conn_write.flush().await {
-                                Err(e) => break Err(Error::ConnectionClosed(e)),
-                                _ => {}
-                            }
-                        }
-                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break Ok(()),
-                        Err(e) => break Err(Error::InputError(e)),
-                    }
-                }
-            })
-            .await
-            .unwrap()
-        };
+/// Connect the shell to our stdin and stdout.
+async fn piped(conn: Connection) -> Result<(), Error> {
+    // Split into read and write halves
+    let (conn_read, mut conn_write) = tokio::io::split(conn);
 
-        let s2c = async {
+    let c2s = async move {
+        tokio::task::spawn(async move {
             let mut buf = [0u8; 1024];
-            let mut stdout = tokio::io::stdout();
+            let mut stdin = std::io::stdin();
             loop {
-                match conn_read.read(&mut buf).await {
+                match tokio::task::block_in_place(|| stdin.read(&mut buf)) {
                     Ok(0) => break Ok(()),
                     Ok(n) => {
-                        match stdout.write_all(&buf[0..n]).await {
-                            Err(e) => break Err(Error::OutputError(e)),
+                        match conn_write.write_all(&buf[0..n]).await {
+                            Err(e) => break Err(Error::ConnectionClosed(e)),
                             _ => {}
                         };
-                        match stdout.flush().await {
-                            Err(e) => break Err(Error::OutputError(e)),
+                        match conn_write.flush().await {
+                            Err(e) => break Err(Error::ConnectionClosed(e)),
                             _ => {}
-                        };
+                        }
                     }
-                    Err(e) => break Err(Error::ConnectionClosed(e)),
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break Ok(()),
+                    Err(e) => break Err(Error::InputError(e)),
                 }
             }
-        };
-
-        // Wait until either c2s ends normally or s2c hits an error
-        pin_mut!(s2c);
-        pin_mut!(c2s);
-        let result: Result<(), Error> = match future::try_select(c2s, s2c).await {
-            Ok(_) => Ok(()),
-            Err(Either::Left((e, _))) | Err(Either::Right((e, _))) => Err(e),
-        };
-
-        context.output(CleaningUpMessage { id })?;
-
-        // Clean up on a best-effort basis
-        let eap = EndPackage::new(id).into_eap();
-        let _ = applications.upload(&eap).await;
+        })
+        .await
+        .unwrap()
+    };
+
+    let s2c = copy_to_stdout(conn_read);
+
+    // Wait until either c2s ends normally or s2c hits an error
+    pin_mut!(s2c);
+    pin_mut!(c2s);
+    match future::try_select(c2s, s2c).await {
+        Ok(_) => Ok(()),
+        Err(Either::Left((e, _))) | Err(Either::Right((e, _))) => Err(e),
+    }
+}
 
-        return result;
+async fn copy_to_stdout<R: tokio::io::AsyncRead + Unpin>(mut conn_read: R) -> Result<(), Error> {
+    let mut buf = [0u8; 1024];
+    let mut stdout = tokio::io::stdout();
+    loop {
+        match conn_read.read(&mut buf).await {
+            Ok(0) => break Ok(()),
+            Ok(n) => {
+                match stdout.write_all(&buf[0..n]).await {
+                    Err(e) => break Err(Error::OutputError(e)),
+                    _ => {}
+                };
+                match stdout.flush().await {
+                    Err(e) => break Err(Error::OutputError(e)),
+                    _ => {}
+                };
+            }
+            Err(e) => break Err(Error::ConnectionClosed(e)),
+        }
     }
 }
 
@@ -20,12 +20,20 @@ impl EndPackage {
         format!("/tmp/trunnion-shell.{}", self.id)
     }
 
+    fn rundir(&self) -> String {
+        format!("{}.run", self.workdir())
+    }
+
     fn package_dot_conf(&self) -> Vec<u8> {
         format!(
             r#"(
     workdir={}
-    [ -f $workdir/openssl.pid ] && kill `cat $workdir/openssl.pid`
-    [ -f $workdir/stunnel.pid ] && kill `cat $workdir/stunnel.pid`
+    rundir={}
+    for pidfile in $rundir/*.pid
+    do
+        [ -f $pidfile ] && kill `cat $pidfile`
+    done
+    [ -d $rundir ] && rm -r $rundir
     [ -d $workdir ] && rm -r $workdir
     echo "terminated"
 ) | logger -t 'trunnion shell {}' &
@@ -33,6 +41,7 @@ impl EndPackage {
 false
 "#,
             &self.workdir(),
+            &self.rundir(),
             &self.id,
         )
         .into_bytes()
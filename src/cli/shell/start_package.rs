@@ -5,12 +5,22 @@ use uuid::Uuid;
 pub struct StartPackage {
     id: Uuid,
     port: u16,
+    pty: Option<Pty>,
     server_pem: Vec<u8>,
     client_ca_pem: Vec<u8>,
 }
 
+/// A pseudo-terminal to allocate for the shell.
+#[derive(Debug)]
+pub struct Pty {
+    /// The terminal type, i.e. `TERM`
+    pub term: String,
+    /// The port on which to accept window size changes
+    pub resize_port: u16,
+}
+
 impl StartPackage {
-    pub fn new(id: Uuid, port: u16, server: &Endpoint) -> Self {
+    pub fn new(id: Uuid, port: u16, pty: Option<Pty>, server: &Endpoint) -> Self {
         let server_pem = {
             let key = server
                 .key
@@ -31,6 +41,7 @@ impl StartPackage {
         StartPackage {
             id,
             port,
+            pty,
             server_pem,
             client_ca_pem,
         }
@@ -39,13 +50,19 @@ impl StartPackage {
     pub fn into_eap(self) -> Vec<u8> {
         use crate::tar::*;
 
-        build(&[
+        let mut files = vec![
             file("package.conf", self.package_dot_conf()),
             executable(self.run_sh_filename(), self.run_sh()),
             file("stunnel.conf", self.stunnel_config()),
             file("server.pem", &self.server_pem),
             file("client_ca.pem", &self.client_ca_pem),
-        ])
+        ];
+        if let Some(pty) = &self.pty {
+            files.push(executable("pty.sh", self.pty_sh(pty)));
+            files.push(executable("resize.sh", self.resize_sh()));
+        }
+
+        build(&files)
     }
 
     fn run_sh_filename(&self) -> String {
@@ -82,12 +99,23 @@ false
 # trunnion shell invocation
 id={}
 workdir={}
+rundir={}
 ssl_port={}
+resize_port={}
+pty={}
+
+# exec a command, first recording its PID so that the end package can kill it
+record() {{
+  pidfile=$rundir/$1.pid
+  shift
+  sh -c 'echo $$ >"$0" && exec "$@"' $pidfile "$@"
+}}
 
 cd `dirname $0`
 
-mkdir $workdir
+mkdir $workdir $rundir
 mv server.pem client_ca.pem stunnel.conf $workdir/
+[ -n "$pty" ] && mv pty.sh resize.sh $rundir/
 
 export HOME=/root
 export PATH=$PATH:/usr/sbin
@@ -103,9 +131,35 @@ elif command -v openssl >/dev/null 2>&1
 then
   echo 'starting sh-over-SSL via `openssl` on port '$ssl_port
 
+  # `script` gives the shell a pseudo-terminal; without it, there's no pseudo-terminal and no
+  # window size changes to listen for, which tells the client to do without
+  if [ -n "$pty" ] && ! command -v script >/dev/null
+  then
+    echo '`script` is not available, so the shell has no pseudo-terminal'
+    pty=
+  fi
+
+  shell='sh -i'
+  if [ -n "$pty" ]
+  then
+    shell="script -qc $rundir/pty.sh /dev/null"
+
+    # accept a single connection for window size changes; stdin stays open but silent
+    mkfifo $workdir/resize.in
+    record resize-server openssl s_server -quiet -naccept 1 \
+        -port $resize_port \
+        -cert $workdir/server.pem \
+        -key $workdir/server.pem \
+        -CAfile $workdir/client_ca.pem \
+        -Verify 1 \
+        -verify_return_error \
+        <>$workdir/resize.in 2>/dev/null | \
+        record resize $rundir/resize.sh &
+  fi
+
   mkfifo $workdir/c2s
-  sh -i <$workdir/c2s 2>&1 | \
-      openssl s_server -quiet \
+  record shell $shell <$workdir/c2s 2>&1 | \
+      record openssl openssl s_server -quiet \
       -port $ssl_port \
       -cert $workdir/server.pem \
       -key $workdir/server.pem \
@@ -117,6 +171,7 @@ else
   echo 'fatal: `stunnel` and `openssl` are not available'
 fi
 
+# remove the keys once they're loaded, leaving $rundir for the end package to clean up
 sleep 10
 rm -r $workdir
 
@@ -124,20 +179,78 @@ false
 "#,
             self.id,
             self.workdir(),
+            self.rundir(),
             self.port,
+            self.pty.as_ref().map(|pty| pty.resize_port).unwrap_or(0),
+            if self.pty.is_some() { "yes" } else { "" },
+        )
+        .into_bytes()
+    }
+
+    /// The shell to run on a pseudo-terminal.
+    ///
+    /// This records the name of the terminal so that `resize.sh` can find it.
+    fn pty_sh(&self, pty: &Pty) -> Vec<u8> {
+        format!(
+            r#"#!/bin/sh
+tty=`tty` && echo $tty > {rundir}/tty
+export TERM='{term}'
+exec sh -i
+"#,
+            rundir = self.rundir(),
+            term = pty.term,
+        )
+        .into_bytes()
+    }
+
+    /// Read `rows cols` lines and apply them to the shell's pseudo-terminal.
+    fn resize_sh(&self) -> Vec<u8> {
+        format!(
+            r#"#!/bin/sh
+rundir={}
+
+# wait for the shell to start, but not forever
+tries=0
+while [ ! -s $rundir/tty ]
+do
+  tries=$((tries + 1))
+  [ $tries -gt 10 ] && exit 1
+  sleep 1
+done
+tty=`cat $rundir/tty`
+
+while read rows cols
+do
+  case "$rows,$cols" in
+    *[!0-9,]*|,*|*,) ;;
+    *) stty -F $tty rows $rows cols $cols ;;
+  esac
+done
+"#,
+            self.rundir(),
         )
         .into_bytes()
     }
 
+    /// Where the keys and configuration are kept until the shell has started.
     fn workdir(&self) -> String {
         format!("/tmp/trunnion-shell.{}", self.id)
     }
 
+    /// Where PID files and anything needed for the rest of the session are kept.
+    fn rundir(&self) -> String {
+        format!("{}.run", self.workdir())
+    }
+
     fn stunnel_config(&self) -> Vec<u8> {
         let workdir = self.workdir();
+        let rundir = self.rundir();
+
+        match &self.pty {
+            None => format!(
+                r#"
+pid      = {}/stunnel.pid
 
-        format!(
-            r#"
 [sh]
 accept   = {}
 exec     = /bin/sh
@@ -146,8 +259,33 @@ cert     = {}/server.pem
 CAfile   = {}/client_ca.pem
 verifyChain = yes
 "#,
-            self.port, &workdir, &workdir,
-        )
+                &rundir, self.port, &workdir, &workdir,
+            ),
+            Some(pty) => format!(
+                r#"
+pid      = {rundir}/stunnel.pid
+
+[sh]
+accept   = {port}
+exec     = {rundir}/pty.sh
+pty      = yes
+cert     = {workdir}/server.pem
+CAfile   = {workdir}/client_ca.pem
+verifyChain = yes
+
+[resize]
+accept   = {resize_port}
+exec     = {rundir}/resize.sh
+cert     = {workdir}/server.pem
+CAfile   = {workdir}/client_ca.pem
+verifyChain = yes
+"#,
+                port = self.port,
+                resize_port = pty.resize_port,
+                workdir = &workdir,
+                rundir = &rundir,
+            ),
+        }
         .into_bytes()
     }
 }
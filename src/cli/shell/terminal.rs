@@ -0,0 +1,92 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Puts the local terminal into raw mode, restoring it when dropped.
+#[derive(Debug)]
+pub struct RawMode(());
+
+impl RawMode {
+    pub fn enable() -> Result<Self, crossterm::ErrorKind> {
+        crossterm::terminal::enable_raw_mode()?;
+        Ok(RawMode(()))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+}
+
+/// Encode a key event as the bytes an xterm would send for it.
+///
+/// crossterm parses our terminal's input into events, so this turns them back into input for the
+/// remote terminal.
+pub fn encode_key(key: KeyEvent) -> Vec<u8> {
+    let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+    let control = key.modifiers.contains(KeyModifiers::CONTROL);
+    let alt = key.modifiers.contains(KeyModifiers::ALT);
+
+    // xterm reports modifiers on special keys as a parameter
+    let modifier = 1 + shift as u8 + 2 * alt as u8 + 4 * control as u8;
+    let csi = |suffix: char| match modifier {
+        1 => format!("\x1b[{}", suffix),
+        m => format!("\x1b[1;{}{}", m, suffix),
+    };
+    let tilde = |n: u8| match modifier {
+        1 => format!("\x1b[{}~", n),
+        m => format!("\x1b[{};{}~", n, m),
+    };
+
+    let bytes = match key.code {
+        KeyCode::Left => return csi('D').into_bytes(),
+        KeyCode::Right => return csi('C').into_bytes(),
+        KeyCode::Up => return csi('A').into_bytes(),
+        KeyCode::Down => return csi('B').into_bytes(),
+        KeyCode::Home => return csi('H').into_bytes(),
+        KeyCode::End => return csi('F').into_bytes(),
+        KeyCode::Insert => return tilde(2).into_bytes(),
+        KeyCode::Delete => return tilde(3).into_bytes(),
+        KeyCode::PageUp => return tilde(5).into_bytes(),
+        KeyCode::PageDown => return tilde(6).into_bytes(),
+        KeyCode::BackTab => return b"\x1b[Z".to_vec(),
+        KeyCode::F(n @ 1..=4) if modifier == 1 => {
+            return format!("\x1bO{}", (b'P' + n - 1) as char).into_bytes()
+        }
+        KeyCode::F(n @ 1..=4) => return csi((b'P' + n - 1) as char).into_bytes(),
+        KeyCode::F(n) => {
+            return match n {
+                5 => tilde(15),
+                6..=10 => tilde(n + 11),
+                11..=12 => tilde(n + 12),
+                _ => String::new(),
+            }
+            .into_bytes()
+        }
+
+        KeyCode::Backspace => vec![0x7f],
+        KeyCode::Enter => vec![b'\r'],
+        KeyCode::Tab => vec![b'\t'],
+        KeyCode::Esc => vec![0x1b],
+        KeyCode::Null => vec![0],
+        KeyCode::Char(c) if control => match c {
+            'a'..='z' => vec![c as u8 - b'a' + 1],
+            'A'..='Z' => vec![c as u8 - b'A' + 1],
+            ' ' | '@' | '2' => vec![0],
+            '[' | '3' => vec![0x1b],
+            '\\' | '4' => vec![0x1c],
+            ']' | '5' => vec![0x1d],
+            '^' | '6' => vec![0x1e],
+            '_' | '7' => vec![0x1f],
+            '?' | '8' => vec![0x7f],
+            c => c.to_string().into_bytes(),
+        },
+        KeyCode::Char(c) => c.to_string().into_bytes(),
+    };
+
+    // Alt prefixes ordinary keys with an escape
+    if alt {
+        std::iter::once(0x1b).chain(bytes).collect()
+    } else {
+        bytes
+    }
+}
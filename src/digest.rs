@@ -0,0 +1,149 @@
+//! HTTP digest authentication with credentials given separately, rather than in the device URL.
+//!
+//! `vapix::Client` only takes credentials from the URL, which means they have to survive being
+//! written into one. This transport answers the device's digest challenges itself instead, so
+//! `vapix` only sees a challenge if the credentials are refused.
+
+use crate::https;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::sync::{Arc, Mutex};
+
+/// A username and password for a device.
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<hidden>")
+            .finish()
+    }
+}
+
+/// A `vapix::Transport` which authenticates with `Credentials`, if it has any.
+pub struct Transport {
+    inner: Arc<vapix::HyperTransport<https::Connector>>,
+    authentication: Option<Arc<Authentication>>,
+}
+
+impl Transport {
+    pub fn new(client: hyper::Client<https::Connector>, credentials: Option<Credentials>) -> Self {
+        Self {
+            inner: Arc::new(vapix::HyperTransport::new(client)),
+            authentication: credentials.map(|credentials| {
+                Arc::new(Authentication {
+                    credentials,
+                    challenge: Mutex::new(None),
+                })
+            }),
+        }
+    }
+}
+
+struct Authentication {
+    credentials: Credentials,
+    /// The most recent challenge from the device, which is good for more than one request
+    challenge: Mutex<Option<digest_auth::WwwAuthenticateHeader>>,
+}
+
+impl Authentication {
+    /// Remember the challenge in a response, returning whether it was refused for the lack of one.
+    fn challenged(&self, response: &http::Response<hyper::Body>) -> bool {
+        let challenge = response
+            .headers()
+            .get(http::header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| digest_auth::WwwAuthenticateHeader::parse(value).ok());
+        match challenge {
+            Some(challenge) => {
+                *self.challenge.lock().unwrap() = Some(challenge);
+                response.status() == http::StatusCode::UNAUTHORIZED
+            }
+            None => false,
+        }
+    }
+
+    /// Answer the latest challenge for a request, if there's been one.
+    fn authorize(&self, request: &mut http::Request<Vec<u8>>) {
+        let method = match request.method().as_str() {
+            "GET" => digest_auth::HttpMethod::GET,
+            "HEAD" => digest_auth::HttpMethod::HEAD,
+            "POST" => digest_auth::HttpMethod::POST,
+            "PUT" => digest_auth::HttpMethod::OTHER("PUT"),
+            "DELETE" => digest_auth::HttpMethod::OTHER("DELETE"),
+            "PATCH" => digest_auth::HttpMethod::OTHER("PATCH"),
+            _ => digest_auth::HttpMethod::POST,
+        };
+        let uri = request
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let body = Some(request.body().as_slice()).filter(|body| !body.is_empty());
+        let context = digest_auth::AuthContext::new_with_method(
+            self.credentials.username.as_str(),
+            self.credentials.password.as_str(),
+            uri,
+            body,
+            method,
+        );
+
+        let authorization = self
+            .challenge
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|challenge| challenge.respond(&context).ok())
+            .and_then(|header| http::HeaderValue::from_str(&header.to_header_string()).ok());
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert(http::header::AUTHORIZATION, authorization);
+        }
+    }
+}
+
+/// A copy of a request, since the first attempt consumes it.
+fn duplicate(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut duplicate = http::Request::new(request.body().clone());
+    *duplicate.method_mut() = request.method().clone();
+    *duplicate.uri_mut() = request.uri().clone();
+    *duplicate.version_mut() = request.version();
+    *duplicate.headers_mut() = request.headers().clone();
+    duplicate
+}
+
+impl vapix::Transport for Transport {
+    type Error = hyper::Error;
+    type Output = BoxFuture<'static, Result<http::Response<hyper::Body>, hyper::Error>>;
+    type Body = hyper::Body;
+    type Chunk = hyper::body::Bytes;
+
+    fn roundtrip(&self, mut request: http::Request<Vec<u8>>) -> Self::Output {
+        let authentication = match &self.authentication {
+            Some(authentication) => authentication.clone(),
+            None => return self.inner.roundtrip(request).boxed(),
+        };
+        let inner = self.inner.clone();
+
+        async move {
+            let mut retry = duplicate(&request);
+            authentication.authorize(&mut request);
+            let response = inner.roundtrip(request).await?;
+            if !authentication.challenged(&response) {
+                return Ok(response);
+            }
+
+            // Answer the new challenge, but only once: a second refusal means the credentials
+            // are wrong, which is for the caller to report
+            authentication.authorize(&mut retry);
+            inner.roundtrip(retry).await
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,535 @@
+//! Finding devices on the local network.
+//!
+//! AXIS devices announce `_axis-video._tcp` over mDNS and `urn:axis-com:service:BasicService:1`
+//! over SSDP. Neither announcement says which firmware a device runs, so each device which answers
+//! is then asked over HTTP, using the part of the basic device info API which needs no credentials.
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+mod mdns;
+pub mod responder;
+mod ssdp;
+
+pub const MDNS_ADDRESS: &str = "224.0.0.251:5353";
+pub const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+
+/// The mDNS service which AXIS devices announce.
+pub const SERVICE: &str = "_axis-video._tcp.local";
+/// The SSDP search target which AXIS devices answer to.
+pub const SEARCH_TARGET: &str = "urn:axis-com:service:BasicService:1";
+
+/// How long to wait before repeating queries, in case the first ones were lost.
+const REPEAT_AFTER: Duration = Duration::from_secs(1);
+/// How long to wait for a device to describe itself over HTTP.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error searching for devices: {0}")]
+    SocketError(#[from] std::io::Error),
+}
+
+/// A device which answered a search.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Device {
+    /// The serial number, which for AXIS devices is also the MAC address
+    pub serial: Option<String>,
+    pub model: Option<String>,
+    pub ip: IpAddr,
+    /// The port of the device's web interface
+    pub port: u16,
+    pub firmware: Option<String>,
+    /// The protocols by which the device was found
+    pub found_by: Vec<&'static str>,
+}
+
+impl Device {
+    /// The device URL, without credentials.
+    pub fn url(&self) -> String {
+        let host = match self.ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        };
+        match self.port {
+            80 => format!("http://{}/", host),
+            port => format!("http://{}:{}/", host, port),
+        }
+    }
+
+    fn is_same(&self, other: &Device) -> bool {
+        match (&self.serial, &other.serial) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.ip == other.ip,
+        }
+    }
+
+    /// Fill in whatever another answer from the same device knows that this one doesn't.
+    fn merge(&mut self, other: Device) {
+        self.serial = self.serial.take().or(other.serial);
+        self.model = self.model.take().or(other.model);
+        self.firmware = self.firmware.take().or(other.firmware);
+        // SSDP doesn't say where the web interface is, so prefer what mDNS said
+        if other.found_by.contains(&"mdns") && !self.found_by.contains(&"mdns") {
+            self.port = other.port;
+        }
+        for protocol in other.found_by {
+            if !self.found_by.contains(&protocol) {
+                self.found_by.push(protocol);
+            }
+        }
+    }
+}
+
+/// Tidy a serial number or MAC address into the form AXIS prints on labels, i.e. `ACCC8E7A1B2C`.
+fn normalize_serial(s: &str) -> Option<String> {
+    let serial: String = s
+        .chars()
+        .filter(|c| *c != ':' && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if serial.len() == 12 && serial.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(serial)
+    } else {
+        None
+    }
+}
+
+/// Where to search and for how long.
+#[derive(Debug, Clone)]
+pub struct Search {
+    /// Where to send mDNS queries, if anywhere
+    pub mdns: Option<SocketAddr>,
+    /// Where to send SSDP searches, if anywhere
+    pub ssdp: Option<SocketAddr>,
+    /// How long to collect answers
+    pub wait: Duration,
+}
+
+impl Search {
+    /// Search, returning the devices which answered in order of IP address.
+    pub async fn run(&self) -> Result<Vec<Device>, Error> {
+        let mut socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+
+        let started = tokio::time::Instant::now();
+        let deadline = started + self.wait;
+        let mut repeat_at = Some(started + REPEAT_AFTER).filter(|at| *at < deadline);
+
+        self.send(&mut socket).await?;
+
+        let mut devices: Vec<Device> = Vec::new();
+        let mut buf = vec![0u8; 9000];
+        loop {
+            let until = repeat_at.unwrap_or(deadline);
+            let (len, from) = match tokio::time::timeout_at(until, socket.recv_from(&mut buf)).await
+            {
+                Ok(received) => received?,
+                Err(_) if repeat_at.is_some() => {
+                    repeat_at = None;
+                    self.send(&mut socket).await?;
+                    continue;
+                }
+                Err(_) => break,
+            };
+
+            for device in parse_answer(&buf[..len], from) {
+                match devices.iter_mut().find(|d| d.is_same(&device)) {
+                    Some(existing) => existing.merge(device),
+                    None => devices.push(device),
+                }
+            }
+        }
+
+        join_all(devices.iter_mut().map(probe)).await;
+
+        devices.sort_by_key(|d| d.ip);
+        Ok(devices)
+    }
+
+    async fn send(&self, socket: &mut UdpSocket) -> Result<(), Error> {
+        if let Some(to) = self.mdns {
+            socket
+                .send_to(&mdns::Message::query(SERVICE).encode(), &to)
+                .await?;
+        }
+        if let Some(to) = self.ssdp {
+            socket
+                .send_to(&ssdp::search(to, SEARCH_TARGET), &to)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Make sense of a packet received in answer to a search.
+fn parse_answer(packet: &[u8], from: SocketAddr) -> Vec<Device> {
+    if let Some(message) = ssdp::Message::parse(packet) {
+        return from_ssdp(&message, from).into_iter().collect();
+    }
+
+    match mdns::Message::parse(packet) {
+        Some(message) if message.response => from_mdns(&message, from),
+        _ => Vec::new(),
+    }
+}
+
+fn from_ssdp(message: &ssdp::Message, from: SocketAddr) -> Option<Device> {
+    if message.is_search()
+        || !message.is_success()
+        || !message.header("ST")?.eq_ignore_ascii_case(SEARCH_TARGET)
+    {
+        return None;
+    }
+
+    // i.e. uuid:Upnp-BasicDevice-1_0-ACCC8E7A1B2C::urn:axis-com:service:BasicService:1
+    let serial = message
+        .header("USN")
+        .and_then(|usn| usn.split("::").next())
+        .and_then(|uuid| uuid.rsplit('-').next())
+        .and_then(normalize_serial);
+
+    // The location points at the UPnP description, which is on the device but not necessarily on
+    // the port of its web interface
+    let ip = message
+        .header("LOCATION")
+        .and_then(|location| location.parse::<http::Uri>().ok())
+        .and_then(|uri| {
+            uri.host()
+                .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+                .and_then(|host| host.parse().ok())
+        })
+        .unwrap_or_else(|| from.ip());
+
+    Some(Device {
+        serial,
+        model: None,
+        ip,
+        port: 80,
+        firmware: None,
+        found_by: vec!["ssdp"],
+    })
+}
+
+fn from_mdns(message: &mdns::Message, from: SocketAddr) -> Vec<Device> {
+    use mdns::Data;
+
+    let named = |name| records_named(&message.records, name);
+
+    named(SERVICE)
+        .filter_map(|data| match data {
+            Data::Ptr(instance) => Some(instance),
+            _ => None,
+        })
+        .map(|instance| {
+            let (port, target) = named(instance)
+                .find_map(|data| match data {
+                    Data::Srv { port, target } => Some((*port, Some(target.as_str()))),
+                    _ => None,
+                })
+                .unwrap_or((80, None));
+
+            let ip = target
+                .and_then(|target| {
+                    named(target).find_map(|data| match data {
+                        Data::A(ip) => Some(IpAddr::V4(*ip)),
+                        Data::Aaaa(ip) => Some(IpAddr::V6(*ip)),
+                        _ => None,
+                    })
+                })
+                .unwrap_or_else(|| from.ip());
+
+            let mac = named(instance)
+                .filter_map(|data| match data {
+                    Data::Txt(strings) => Some(strings),
+                    _ => None,
+                })
+                .flatten()
+                .find_map(|s| s.strip_prefix("macaddress="))
+                .and_then(normalize_serial);
+
+            // Instances are named like `AXIS M3045-V - ACCC8E7A1B2C` unless someone renamed them
+            let label = instance
+                .strip_suffix(SERVICE)
+                .unwrap_or(instance)
+                .trim_end_matches('.');
+            let mut parts = label.rsplitn(2, " - ");
+            let (suffix, prefix) = (parts.next(), parts.next());
+            let serial = mac.or_else(|| suffix.and_then(normalize_serial));
+            let model = prefix.map(|model| model.trim_start_matches("AXIS ").to_owned());
+
+            Device {
+                serial,
+                model,
+                ip,
+                port,
+                firmware: None,
+                found_by: vec!["mdns"],
+            }
+        })
+        .collect()
+}
+
+/// The data of the records with a name.
+fn records_named<'a>(
+    records: &'a [mdns::Record],
+    name: &'a str,
+) -> impl Iterator<Item = &'a mdns::Data> + 'a {
+    records
+        .iter()
+        .filter(move |r| r.name.eq_ignore_ascii_case(name))
+        .map(|r| &r.data)
+}
+
+/// The basic device info properties which can be read without credentials.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UnrestrictedProperties {
+    #[serde(rename = "ProdNbr", default, skip_serializing_if = "Option::is_none")]
+    pub product_number: Option<String>,
+    #[serde(
+        rename = "ProdFullName",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub product_full_name: Option<String>,
+    #[serde(
+        rename = "SerialNumber",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub serial_number: Option<String>,
+    #[serde(rename = "Version", default, skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<String>,
+}
+
+/// Ask a device to describe itself, filling in what the announcements left out.
+///
+/// Devices which don't answer, or don't speak plain HTTP, are left as they were.
+async fn probe(device: &mut Device) {
+    #[derive(Deserialize)]
+    struct Resp {
+        data: Data,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Data {
+        property_list: UnrestrictedProperties,
+    }
+
+    let request = http::Request::post(format!("{}axis-cgi/basicdeviceinfo.cgi", device.url()))
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(
+            r#"{"apiVersion":"1.0","method":"getAllUnrestrictedProperties"}"#,
+        ))
+        .expect("request is valid");
+
+    let properties = async {
+        let response = hyper::Client::new().request(request).await.ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await.ok()?;
+        serde_json::from_slice::<Resp>(&body)
+            .ok()
+            .map(|resp| resp.data.property_list)
+    };
+
+    if let Ok(Some(properties)) = tokio::time::timeout(PROBE_TIMEOUT, properties).await {
+        if let Some(serial) = properties
+            .serial_number
+            .as_deref()
+            .and_then(normalize_serial)
+        {
+            device.serial = Some(serial);
+        }
+        device.model = properties.product_number.or_else(|| device.model.take());
+        device.firmware = properties.firmware_version;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::responder::Responder;
+    use super::*;
+
+    const FROM: ([u8; 4], u16) = ([192, 168, 0, 90], 5353);
+
+    #[test]
+    fn normalize_serials() {
+        assert_eq!(
+            normalize_serial("ACCC8E7A1B2C").as_deref(),
+            Some("ACCC8E7A1B2C")
+        );
+        assert_eq!(
+            normalize_serial("ac:cc:8e:7a:1b:2c").as_deref(),
+            Some("ACCC8E7A1B2C")
+        );
+        assert_eq!(
+            normalize_serial("AC-CC-8E-7A-1B-2C").as_deref(),
+            Some("ACCC8E7A1B2C")
+        );
+        assert_eq!(normalize_serial("ACCC8E7A1B2"), None);
+        assert_eq!(normalize_serial("ACCC8E7A1B2CD"), None);
+        assert_eq!(normalize_serial("lobby camera"), None);
+    }
+
+    #[test]
+    fn ssdp_responses() {
+        let packet = ssdp::response(
+            "http://192.168.0.91:49152/rootdesc1.xml",
+            SEARCH_TARGET,
+            &format!("uuid:Upnp-BasicDevice-1_0-accc8e7a1b2c::{}", SEARCH_TARGET),
+        );
+        let message = ssdp::Message::parse(&packet).unwrap();
+        assert_eq!(
+            from_ssdp(&message, FROM.into()),
+            Some(Device {
+                serial: Some("ACCC8E7A1B2C".into()),
+                model: None,
+                ip: [192, 168, 0, 91].into(),
+                port: 80,
+                firmware: None,
+                found_by: vec!["ssdp"],
+            })
+        );
+
+        // Without a usable location, the device is wherever the answer came from
+        let packet = ssdp::response("rootdesc1.xml", SEARCH_TARGET, "uuid:unknown");
+        let device = from_ssdp(&ssdp::Message::parse(&packet).unwrap(), FROM.into()).unwrap();
+        assert_eq!(device.serial, None);
+        assert_eq!(device.ip, IpAddr::from(FROM.0));
+
+        // Searches and answers for other targets aren't devices
+        let search = ssdp::search(SSDP_ADDRESS.parse().unwrap(), SEARCH_TARGET);
+        assert_eq!(
+            from_ssdp(&ssdp::Message::parse(&search).unwrap(), FROM.into()),
+            None
+        );
+        let other = ssdp::response("http://192.168.0.91/", "upnp:rootdevice", "uuid:x");
+        assert_eq!(
+            from_ssdp(&ssdp::Message::parse(&other).unwrap(), FROM.into()),
+            None
+        );
+    }
+
+    fn record(name: &str, data: mdns::Data) -> mdns::Record {
+        mdns::Record {
+            name: name.into(),
+            ttl: 120,
+            data,
+        }
+    }
+
+    #[test]
+    fn mdns_responses() {
+        let instance = format!("AXIS M3045-V - ACCC8E7A1B2C.{}", SERVICE);
+        let message = mdns::Message {
+            id: 0,
+            response: true,
+            questions: Vec::new(),
+            records: vec![
+                record(SERVICE, mdns::Data::Ptr(instance.clone())),
+                record(
+                    &instance,
+                    mdns::Data::Srv {
+                        port: 8080,
+                        target: "axis-accc8e7a1b2c.local".into(),
+                    },
+                ),
+                record(
+                    &instance,
+                    mdns::Data::Txt(vec!["macaddress=AC:CC:8E:7A:1B:2D".into()]),
+                ),
+                record(
+                    "axis-accc8e7a1b2c.local",
+                    mdns::Data::A([192, 168, 0, 91].into()),
+                ),
+            ],
+        };
+        assert_eq!(
+            from_mdns(&message, FROM.into()),
+            vec![Device {
+                // The MAC address in the TXT record is preferred to the instance name
+                serial: Some("ACCC8E7A1B2D".into()),
+                model: Some("M3045-V".into()),
+                ip: [192, 168, 0, 91].into(),
+                port: 8080,
+                firmware: None,
+                found_by: vec!["mdns"],
+            }]
+        );
+
+        // A bare answer falls back to the instance name, port 80 and the sender's address
+        let message = mdns::Message {
+            records: vec![record(SERVICE, mdns::Data::Ptr(instance))],
+            ..message
+        };
+        assert_eq!(
+            from_mdns(&message, FROM.into()),
+            vec![Device {
+                serial: Some("ACCC8E7A1B2C".into()),
+                model: Some("M3045-V".into()),
+                ip: FROM.0.into(),
+                port: 80,
+                firmware: None,
+                found_by: vec!["mdns"],
+            }]
+        );
+
+        // A renamed instance says nothing about the device
+        let message = mdns::Message {
+            records: vec![record(
+                SERVICE,
+                mdns::Data::Ptr(format!("Lobby.{}", SERVICE)),
+            )],
+            ..message
+        };
+        let devices = from_mdns(&message, FROM.into());
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].serial, None);
+    }
+
+    #[tokio::test]
+    async fn search_finds_responder() {
+        let listening = Responder {
+            serial: "ACCC8E7A1B2C".into(),
+            model: "M3045-V".into(),
+            firmware: "9.80.2.2".into(),
+        }
+        .bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+        let address = listening.udp_address();
+        let http_address = listening.http_address();
+        tokio::spawn(listening.run());
+
+        let mut devices = Search {
+            mdns: Some(address),
+            ssdp: Some(address),
+            wait: Duration::from_millis(500),
+        }
+        .run()
+        .await
+        .unwrap();
+        // The answers could arrive in either order
+        for device in &mut devices {
+            device.found_by.sort();
+        }
+
+        assert_eq!(
+            devices,
+            vec![Device {
+                serial: Some("ACCC8E7A1B2C".into()),
+                model: Some("M3045-V".into()),
+                ip: http_address.ip(),
+                port: http_address.port(),
+                firmware: Some("9.80.2.2".into()),
+                found_by: vec!["mdns", "ssdp"],
+            }]
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! Just enough DNS to ask for a service over mDNS and to understand the answers.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+
+const CLASS_IN: u16 = 1;
+/// The top bit of a question's class asks for a unicast response, and the same bit of a record's
+/// class tells caches to flush other records of that name.
+const CLASS_FLAG: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+/// A DNS message, or at least the parts of one that mDNS uses.
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub id: u16,
+    pub response: bool,
+    pub questions: Vec<Question>,
+    /// Answers, authority and additional records, which mDNS treats alike
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Question {
+    pub name: String,
+    pub record_type: u16,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: Data,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Data {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    Other(u16),
+}
+
+impl Message {
+    /// A query for the instances of a service, asking for unicast responses.
+    pub fn query(service: &str) -> Self {
+        Self {
+            id: 0,
+            response: false,
+            questions: vec![Question {
+                name: service.into(),
+                record_type: TYPE_PTR,
+            }],
+            records: Vec::new(),
+        }
+    }
+
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let mut reader = Reader { packet, offset: 0 };
+
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let question_count = reader.u16()?;
+        let record_count = (0..3).try_fold(0usize, |n, _| Some(n + reader.u16()? as usize))?;
+
+        let mut questions = Vec::new();
+        for _ in 0..question_count {
+            let name = reader.name()?;
+            let record_type = reader.u16()?;
+            let _class = reader.u16()?;
+            questions.push(Question { name, record_type });
+        }
+
+        let mut records = Vec::new();
+        for _ in 0..record_count {
+            let name = reader.name()?;
+            let record_type = reader.u16()?;
+            let _class = reader.u16()?;
+            let ttl = reader.u32()?;
+            let len = reader.u16()? as usize;
+            let end = reader.offset.checked_add(len)?;
+            if end > packet.len() {
+                return None;
+            }
+
+            let data = match record_type {
+                TYPE_A if len == 4 => Data::A(Ipv4Addr::new(
+                    packet[end - 4],
+                    packet[end - 3],
+                    packet[end - 2],
+                    packet[end - 1],
+                )),
+                TYPE_AAAA if len == 16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&packet[reader.offset..end]);
+                    Data::Aaaa(octets.into())
+                }
+                TYPE_PTR => Data::Ptr(reader.name()?),
+                TYPE_SRV => {
+                    let _priority = reader.u16()?;
+                    let _weight = reader.u16()?;
+                    let port = reader.u16()?;
+                    let target = reader.name()?;
+                    Data::Srv { port, target }
+                }
+                TYPE_TXT => {
+                    let mut strings = Vec::new();
+                    let mut rest = &packet[reader.offset..end];
+                    while let Some((&len, tail)) = rest.split_first() {
+                        let len = (len as usize).min(tail.len());
+                        strings.push(String::from_utf8_lossy(&tail[..len]).into_owned());
+                        rest = &tail[len..];
+                    }
+                    Data::Txt(strings)
+                }
+                other => Data::Other(other),
+            };
+            reader.offset = end;
+
+            records.push(Record { name, ttl, data });
+        }
+
+        Some(Self {
+            id,
+            response: flags & FLAG_RESPONSE != 0,
+            questions,
+            records,
+        })
+    }
+
+    /// Encode the message, without name compression.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::new();
+        let flags = if self.response {
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE
+        } else {
+            0
+        };
+        for n in &[
+            self.id,
+            flags,
+            self.questions.len() as u16,
+            self.records.len() as u16,
+            0,
+            0,
+        ] {
+            packet.extend_from_slice(&n.to_be_bytes());
+        }
+
+        for question in &self.questions {
+            put_name(&mut packet, &question.name);
+            packet.extend_from_slice(&question.record_type.to_be_bytes());
+            packet.extend_from_slice(&(CLASS_IN | CLASS_FLAG).to_be_bytes());
+        }
+
+        for record in &self.records {
+            put_name(&mut packet, &record.name);
+
+            let mut data = Vec::new();
+            let record_type = match &record.data {
+                Data::A(ip) => {
+                    data.extend_from_slice(&ip.octets());
+                    TYPE_A
+                }
+                Data::Aaaa(ip) => {
+                    data.extend_from_slice(&ip.octets());
+                    TYPE_AAAA
+                }
+                Data::Ptr(name) => {
+                    put_name(&mut data, name);
+                    TYPE_PTR
+                }
+                Data::Srv { port, target } => {
+                    data.extend_from_slice(&[0, 0, 0, 0]);
+                    data.extend_from_slice(&port.to_be_bytes());
+                    put_name(&mut data, target);
+                    TYPE_SRV
+                }
+                Data::Txt(strings) => {
+                    for s in strings {
+                        let s = &s.as_bytes()[..s.len().min(255)];
+                        data.push(s.len() as u8);
+                        data.extend_from_slice(s);
+                    }
+                    TYPE_TXT
+                }
+                Data::Other(record_type) => *record_type,
+            };
+
+            packet.extend_from_slice(&record_type.to_be_bytes());
+            packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+            packet.extend_from_slice(&record.ttl.to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&data);
+        }
+
+        packet
+    }
+}
+
+/// Write a name as a sequence of labels, such that `"a.b.local"` and `"a.b.local."` are the same.
+fn put_name(packet: &mut Vec<u8>, name: &str) {
+    for label in split_name(name) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label);
+    }
+    packet.push(0);
+}
+
+/// Split a name into labels.
+///
+/// Instance names routinely contain spaces and sometimes dots, so only the service and domain
+/// parts of a name like `AXIS M3045-V - ACCC8E7A1B2C._axis-video._tcp.local` are split.
+fn split_name(name: &str) -> Vec<&str> {
+    let name = name.trim_end_matches('.');
+    let mut labels = Vec::new();
+    let mut rest = name;
+    while let Some(i) = rest.find("._") {
+        labels.push(&rest[..i]);
+        rest = &rest[i + 1..];
+    }
+    labels.extend(rest.split('.').filter(|l| !l.is_empty()));
+    labels
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.packet.get(self.offset..self.offset + 2)?;
+        self.offset += 2;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some((self.u16()? as u32) << 16 | self.u16()? as u32)
+    }
+
+    /// Read a possibly compressed name, joining its labels with dots.
+    fn name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut offset = self.offset;
+        let mut jumps = 0;
+
+        loop {
+            let len = *self.packet.get(offset)? as usize;
+            match len {
+                0 => {
+                    if jumps == 0 {
+                        self.offset = offset + 1;
+                    }
+                    break;
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let target = (len & 0x3f) << 8 | *self.packet.get(offset + 1)? as usize;
+                    if jumps == 0 {
+                        self.offset = offset + 2;
+                    }
+                    // A pointer loop would otherwise never end
+                    jumps += 1;
+                    if jumps > 32 {
+                        return None;
+                    }
+                    offset = target;
+                }
+                len if len < 64 => {
+                    let label = self.packet.get(offset + 1..offset + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    offset += 1 + len;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(labels.join("."))
+    }
+}
@@ -0,0 +1,195 @@
+//! A stand-in for a device, which answers searches the way an AXIS device would.
+//!
+//! It listens on an ordinary UDP socket rather than joining the multicast groups, so searches must
+//! be sent to it directly, and its web interface serves nothing but the unauthenticated basic
+//! device info API.
+
+use super::{mdns, ssdp, Error, UnrestrictedProperties, SEARCH_TARGET, SERVICE};
+use futures::future::{select, Either};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+
+/// What the stand-in says about itself.
+#[derive(Debug, Clone)]
+pub struct Responder {
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+}
+
+/// A responder listening for searches.
+pub struct Listening {
+    responder: Responder,
+    udp: UdpSocket,
+    http: AddrIncoming,
+}
+
+impl Responder {
+    /// Listen for searches on a UDP address, and for HTTP on the same IP.
+    pub async fn bind(self, address: SocketAddr) -> Result<Listening, Error> {
+        let udp = UdpSocket::bind(address).await?;
+        let http = AddrIncoming::bind(&SocketAddr::new(address.ip(), 0))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(Listening {
+            responder: self,
+            udp,
+            http,
+        })
+    }
+
+    fn instance(&self) -> String {
+        format!("AXIS {} - {}.{}", self.model, self.serial, SERVICE)
+    }
+
+    fn properties(&self) -> UnrestrictedProperties {
+        UnrestrictedProperties {
+            product_number: Some(self.model.clone()),
+            product_full_name: Some(format!("AXIS {} Network Camera", self.model)),
+            serial_number: Some(self.serial.clone()),
+            firmware_version: Some(self.firmware.clone()),
+        }
+    }
+
+    /// Answer a packet, if it's a search for AXIS devices.
+    fn answer(&self, packet: &[u8], ip: IpAddr, http_port: u16) -> Option<Vec<u8>> {
+        if let Some(message) = ssdp::Message::parse(packet) {
+            let target = message.header("ST")?;
+            if !message.is_search()
+                || !(target.eq_ignore_ascii_case(SEARCH_TARGET) || target == "ssdp:all")
+            {
+                return None;
+            }
+            return Some(ssdp::response(
+                &format!("http://{}/rootdesc1.xml", SocketAddr::new(ip, http_port)),
+                SEARCH_TARGET,
+                &format!(
+                    "uuid:Upnp-BasicDevice-1_0-{}::{}",
+                    self.serial, SEARCH_TARGET
+                ),
+            ));
+        }
+
+        let query = mdns::Message::parse(packet)?;
+        let asked = query.questions.iter().any(|q| {
+            q.name.eq_ignore_ascii_case(SERVICE)
+                && (q.record_type == mdns::TYPE_PTR || q.record_type == 255)
+        });
+        if query.response || !asked {
+            return None;
+        }
+
+        let instance = self.instance();
+        let host = format!("axis-{}.local", self.serial.to_ascii_lowercase());
+        let record = |name: &str, data| mdns::Record {
+            name: name.into(),
+            ttl: 120,
+            data,
+        };
+        let address = match ip {
+            IpAddr::V4(ip) => mdns::Data::A(ip),
+            IpAddr::V6(ip) => mdns::Data::Aaaa(ip),
+        };
+
+        // Echoing the query's ID and questions is what a querier on another port expects
+        Some(
+            mdns::Message {
+                id: query.id,
+                response: true,
+                questions: query.questions,
+                records: vec![
+                    record(SERVICE, mdns::Data::Ptr(instance.clone())),
+                    record(
+                        &instance,
+                        mdns::Data::Srv {
+                            port: http_port,
+                            target: host.clone(),
+                        },
+                    ),
+                    record(
+                        &instance,
+                        mdns::Data::Txt(vec![format!("macaddress={}", self.serial)]),
+                    ),
+                    record(&host, address),
+                ],
+            }
+            .encode(),
+        )
+    }
+}
+
+impl Listening {
+    /// The address on which searches are answered.
+    pub fn udp_address(&self) -> SocketAddr {
+        self.udp.local_addr().expect("socket has an address")
+    }
+
+    /// The address of the web interface.
+    pub fn http_address(&self) -> SocketAddr {
+        self.http.local_addr()
+    }
+
+    /// Answer searches and HTTP requests until something goes wrong.
+    pub async fn run(self) -> Result<(), Error> {
+        let Listening {
+            responder,
+            udp,
+            http,
+        } = self;
+        let http_address = http.local_addr();
+
+        let body = serde_json::json!({
+            "apiVersion": "1.0",
+            "method": "getAllUnrestrictedProperties",
+            "data": { "propertyList": responder.properties() },
+        })
+        .to_string();
+
+        let server = hyper::Server::builder(http).serve(make_service_fn(move |_| {
+            let body = body.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let response = match (req.method(), req.uri().path()) {
+                        (&http::Method::POST, "/axis-cgi/basicdeviceinfo.cgi") => {
+                            Response::builder()
+                                .header(http::header::CONTENT_TYPE, "application/json")
+                                .body(Body::from(body.clone()))
+                        }
+                        _ => Response::builder()
+                            .status(http::StatusCode::NOT_FOUND)
+                            .body(Body::empty()),
+                    };
+                    async move { Ok::<_, Infallible>(response.expect("response is valid")) }
+                }))
+            }
+        }));
+
+        let answering = answer_searches(responder, udp, http_address);
+
+        match select(Box::pin(server), Box::pin(answering)).await {
+            Either::Left((result, _)) => {
+                result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e).into())
+            }
+            Either::Right((result, _)) => result,
+        }
+    }
+}
+
+/// Answer searches for as long as the socket works.
+async fn answer_searches(
+    responder: Responder,
+    mut udp: UdpSocket,
+    http_address: SocketAddr,
+) -> Result<(), Error> {
+    let mut buf = vec![0u8; 9000];
+    loop {
+        let (len, from) = udp.recv_from(&mut buf).await?;
+        if let Some(answer) = responder.answer(&buf[..len], http_address.ip(), http_address.port())
+        {
+            udp.send_to(&answer, &from).await?;
+        }
+    }
+}
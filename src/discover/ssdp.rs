@@ -0,0 +1,80 @@
+//! SSDP searches and responses, which are HTTP messages sent over UDP.
+
+use std::net::SocketAddr;
+
+/// Build an `M-SEARCH` request for a search target.
+pub fn search(to: SocketAddr, target: &str) -> Vec<u8> {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 1\r\n\
+         ST: {}\r\n\
+         \r\n",
+        to, target
+    )
+    .into_bytes()
+}
+
+/// An SSDP message, being either a search or a response to one.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Message {
+    /// The request or status line
+    pub start: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Message {
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(packet).ok()?;
+        let mut lines = text.split("\r\n");
+        let start = lines.next()?.to_owned();
+        if !start.starts_with("M-SEARCH ") && !start.starts_with("HTTP/") {
+            return None;
+        }
+
+        let headers = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                let name = parts.next()?.trim().to_ascii_uppercase();
+                let value = parts.next()?.trim().to_owned();
+                Some((name, value))
+            })
+            .collect();
+
+        Some(Self { start, headers })
+    }
+
+    pub fn is_search(&self) -> bool {
+        self.start.starts_with("M-SEARCH ")
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.start.split(' ').nth(1) == Some("200")
+    }
+
+    /// Find a header by its name, which must be in upper case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Build a response to a search.
+pub fn response(location: &str, target: &str, usn: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+         CACHE-CONTROL: max-age=1800\r\n\
+         EXT:\r\n\
+         LOCATION: {}\r\n\
+         SERVER: Linux, UPnP/1.0, axctl\r\n\
+         ST: {}\r\n\
+         USN: {}\r\n\
+         \r\n",
+        location, target, usn
+    )
+    .into_bytes()
+}
@@ -0,0 +1,114 @@
+//! Reading ACAP application packages.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("package is not a gzipped tar archive: {0}")]
+    NotAnArchive(std::io::Error),
+    #[error("package does not contain a package.conf")]
+    MissingPackageConf,
+    #[error("package.conf is not valid UTF-8")]
+    InvalidPackageConf,
+}
+
+/// An application package, as produced by the ACAP SDK.
+#[derive(Debug)]
+pub struct Package {
+    package_conf: BTreeMap<String, String>,
+}
+
+impl Package {
+    /// Parse a `.eap`, which is a gzipped tar containing (at least) a `package.conf`.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let gz = flate2::read::GzDecoder::new(bytes);
+        let mut archive = ::tar::Archive::new(gz);
+
+        for entry in archive.entries().map_err(Error::NotAnArchive)? {
+            let mut entry = entry.map_err(Error::NotAnArchive)?;
+            let is_package_conf = entry
+                .path()
+                .map_err(Error::NotAnArchive)?
+                .components()
+                .filter(|c| *c != std::path::Component::CurDir)
+                .eq(std::iter::once(std::path::Component::Normal(
+                    "package.conf".as_ref(),
+                )));
+            if !is_package_conf {
+                continue;
+            }
+
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes).map_err(Error::NotAnArchive)?;
+            let package_conf = String::from_utf8(bytes).map_err(|_| Error::InvalidPackageConf)?;
+
+            return Ok(Self {
+                package_conf: parse_package_conf(&package_conf),
+            });
+        }
+
+        Err(Error::MissingPackageConf)
+    }
+
+    /// Retrieve a value from `package.conf`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.package_conf
+            .get(key)
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    /// The short name of the application, i.e. `APPNAME`.
+    pub fn name(&self) -> Option<&str> {
+        self.get("APPNAME")
+    }
+
+    /// The architecture for which the application was built, i.e. `APPTYPE`.
+    ///
+    /// Packages containing only scripts may omit this.
+    pub fn app_type(&self) -> Option<&str> {
+        self.get("APPTYPE")
+    }
+
+    /// The minimum embedded development API version required by the application, i.e.
+    /// `REQEMBDEVVERSION`.
+    pub fn required_embedded_development_version(&self) -> Option<&str> {
+        self.get("REQEMBDEVVERSION")
+    }
+
+    /// The version of the application, assembled from `APP{MAJOR,MINOR,MICRO}VERSION`.
+    pub fn version(&self) -> Option<String> {
+        let major = self.get("APPMAJORVERSION")?;
+        Some(
+            std::iter::once(major)
+                .chain(self.get("APPMINORVERSION"))
+                .chain(self.get("APPMICROVERSION"))
+                .collect::<Vec<_>>()
+                .join("."),
+        )
+    }
+}
+
+/// `package.conf` is a shell fragment consisting of `KEY="value"` lines.
+fn parse_package_conf(s: &str) -> BTreeMap<String, String> {
+    s.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if !key.is_empty() => {
+                    let value = value.trim();
+                    let value = value
+                        .strip_prefix('"')
+                        .and_then(|v| v.strip_suffix('"'))
+                        .unwrap_or(value);
+                    Some((key.to_owned(), value.to_owned()))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
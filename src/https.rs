@@ -0,0 +1,248 @@
+//! HTTPS connections to devices.
+//!
+//! Device certificates are verified against the system's trust store plus an optional CA bundle,
+//! or against pinned SHA-256 fingerprints, or, when explicitly asked, not at all.
+
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error setting up TLS: {0}")]
+    TlsError(openssl::error::ErrorStack),
+    #[error("error loading CA file {0:?}: {1}")]
+    CaFileError(PathBuf, openssl::error::ErrorStack),
+    #[error("TLS handshake with {0} failed: {1}")]
+    HandshakeError(String, String),
+}
+
+/// The SHA-256 fingerprint of a certificate, as printed by `openssl x509 -fingerprint -sha256`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Fingerprint([u8; 32]);
+
+impl std::str::FromStr for Fingerprint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: Vec<u8> = s
+            .chars()
+            .filter(|c| *c != ':')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()
+            .filter(|digits: &Vec<u8>| digits.len() == 64)
+            .ok_or_else(|| format!("{:?} is not a SHA-256 fingerprint of 64 hex digits", s))?;
+
+        let mut fingerprint = [0u8; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            *byte = pair[0] << 4 | pair[1];
+        }
+        Ok(Fingerprint(fingerprint))
+    }
+}
+
+/// A hyper connector for `http://` and `https://` device URLs.
+#[derive(Debug, Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    tls: Arc<SslConnector>,
+    verify_hostname: bool,
+}
+
+impl Connector {
+    /// Create a connector which verifies certificates against the system's trust store and
+    /// `ca_file`, or if any `pins` are given, accepts only those certificates, or with `insecure`,
+    /// accepts any certificate.
+    pub fn new(
+        ca_file: Option<&Path>,
+        pins: &[Fingerprint],
+        insecure: bool,
+    ) -> Result<Self, Error> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(Error::TlsError)?;
+        if let Some(path) = ca_file {
+            builder
+                .set_ca_file(path)
+                .map_err(|e| Error::CaFileError(path.into(), e))?;
+        }
+
+        if insecure {
+            builder.set_verify(SslVerifyMode::NONE);
+        } else if !pins.is_empty() {
+            let pins = pins.to_vec();
+            builder.set_verify_callback(SslVerifyMode::PEER, move |_, ctx| {
+                // A pin stands in for the rest of the chain, so only the device's certificate matters
+                if ctx.error_depth() > 0 {
+                    return true;
+                }
+                ctx.current_cert()
+                    .and_then(|cert| cert.digest(MessageDigest::sha256()).ok())
+                    .map(|digest| pins.iter().any(|pin| pin.0[..] == digest[..]))
+                    .unwrap_or(false)
+            });
+        }
+
+        Ok(Self {
+            http,
+            tls: Arc::new(builder.build()),
+            verify_hostname: !insecure && pins.is_empty(),
+        })
+    }
+}
+
+impl Service<http::Uri> for Connector {
+    type Response = Stream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Stream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: http::Uri) -> Self::Future {
+        let https = uri.scheme() == Some(&http::uri::Scheme::HTTPS);
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let tls = self.tls.clone();
+        let verify_hostname = self.verify_hostname;
+        let connecting = self.http.call(uri);
+
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            if !https {
+                return Ok(Stream::Http(tcp));
+            }
+
+            let mut config = tls.configure()?;
+            config.set_verify_hostname(verify_hostname);
+            let stream = tokio_openssl::connect(config, &host, tcp)
+                .await
+                .map_err(|e| Error::HandshakeError(host.clone(), e.to_string()))?;
+            Ok(Stream::Https(stream))
+        })
+    }
+}
+
+/// A connection to a device, which may or may not be encrypted.
+pub enum Stream {
+    Http(TcpStream),
+    Https(SslStream<TcpStream>),
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut *self {
+            Stream::Http(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Https(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut *self {
+            Stream::Http(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Https(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut *self {
+            Stream::Http(s) => Pin::new(s).poll_flush(cx),
+            Stream::Https(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut *self {
+            Stream::Http(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Https(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "0123456789ABCDEFfedcba98765432100123456789abcdefFEDCBA9876543210";
+
+    fn expected() -> Fingerprint {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&HEX[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        Fingerprint(bytes)
+    }
+
+    #[test]
+    fn fingerprints_with_colons() {
+        // As `openssl x509 -fingerprint -sha256` prints them
+        let pairs: Vec<&str> = (0..32).map(|i| &HEX[i * 2..i * 2 + 2]).collect();
+        assert_eq!(pairs.join(":").parse(), Ok(expected()));
+    }
+
+    #[test]
+    fn fingerprints_without_colons() {
+        assert_eq!(HEX.parse(), Ok(expected()));
+        assert_eq!(expected().0[0], 0x01);
+        assert_eq!(expected().0[31], 0x10);
+    }
+
+    #[test]
+    fn fingerprints_of_the_wrong_length() {
+        for s in &[
+            "",
+            &HEX[..62],
+            &HEX[..63],
+            &format!("{}0", HEX),
+            &format!("{}00", HEX),
+            "01:23:45",
+        ] {
+            assert!(s.parse::<Fingerprint>().is_err(), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn fingerprints_of_other_characters() {
+        for s in &[
+            HEX.replace('F', "G"),
+            format!(" {}", &HEX[1..]),
+            format!("{}-", &HEX[..63]),
+            HEX.replace('0', "\u{660}"),
+        ] {
+            assert!(s.parse::<Fingerprint>().is_err(), "{:?} parsed", s);
+        }
+    }
+}
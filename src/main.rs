@@ -1,6 +1,13 @@
 mod cli;
+mod digest;
+mod discover;
+mod eap;
+mod https;
+mod inventory;
 mod mutual_tls;
 mod output;
+mod profile;
+mod site;
 mod tar;
 
 fn main() {
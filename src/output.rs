@@ -8,8 +8,49 @@ pub enum Level {
 }
 
 pub trait Output: Serialize {
-    fn print(&self, stdout: &mut std::io::Stdout) -> Result<(), crossterm::ErrorKind>;
+    fn print(&self, stdout: &mut dyn std::io::Write) -> Result<(), crossterm::ErrorKind>;
     fn level(&self) -> Level {
         Level::Info
     }
 }
+
+/// Print rows under bold headings, with each column as wide as its widest cell.
+///
+/// `color` chooses the color of each cell from its row and column. Tables are only for terminals:
+/// callers should output each row separately when writing JSON, so that each becomes one line.
+pub fn print_table<R: AsRef<[C]>, C: AsRef<str>>(
+    stdout: &mut dyn std::io::Write,
+    headings: &[&str],
+    rows: &[R],
+    color: impl Fn(usize, usize) -> crossterm::style::Color,
+) -> Result<(), crossterm::ErrorKind> {
+    use crossterm::{queue, style::*};
+
+    let mut widths: Vec<usize> = headings.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.as_ref()) {
+            *width = (*width).max(cell.as_ref().chars().count());
+        }
+    }
+
+    let pad = |cell: &str, width: usize| format!("{:width$}  ", cell, width = width);
+
+    queue!(stdout, SetAttribute(Attribute::Bold))?;
+    for (heading, width) in headings.iter().zip(widths.iter()) {
+        queue!(stdout, Print(pad(heading, *width)))?;
+    }
+    queue!(stdout, SetAttribute(Attribute::Reset), Print("\n"))?;
+
+    for (i, row) in rows.iter().enumerate() {
+        for (j, (cell, width)) in row.as_ref().iter().zip(widths.iter()).enumerate() {
+            queue!(
+                stdout,
+                SetForegroundColor(color(i, j)),
+                Print(pad(cell.as_ref(), *width))
+            )?;
+        }
+        queue!(stdout, ResetColor, Print("\n"))?;
+    }
+
+    Ok(())
+}
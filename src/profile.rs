@@ -0,0 +1,314 @@
+//! Connection profiles, which name devices and keep their credentials out of URLs.
+//!
+//! Profiles are stored in `$XDG_CONFIG_HOME/axctl/profiles.toml`:
+//!
+//! ```toml
+//! [profiles.lobby]
+//! url = "http://172.16.4.30"
+//! username = "root"
+//! password_file = "/home/me/.config/axctl/passwords/lobby"
+//!
+//! [profiles.dock]
+//! url = "http://172.16.4.31"
+//! password_command = "pass show cameras/dock"
+//!
+//! [profiles.lab]
+//! url = "http://192.168.0.90"
+//! ```
+//!
+//! A profile with neither `password_file` nor `password_command` prompts for its password each
+//! time it's used.
+
+use crate::digest::Credentials;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("unable to find a configuration directory; set XDG_CONFIG_HOME or HOME")]
+    NoConfigDir,
+    #[error("error reading profiles {0:?}: {1}")]
+    ReadError(PathBuf, std::io::Error),
+    #[error("error parsing profiles {0:?}: {1}")]
+    ParseError(PathBuf, toml::de::Error),
+    #[error("error writing profiles {0:?}: {1}")]
+    WriteError(PathBuf, std::io::Error),
+    #[error("there is no profile named {0:?}")]
+    UnknownProfile(String),
+    #[error("a profile named {0:?} already exists")]
+    DuplicateProfile(String),
+    #[error("{0:?} is not a valid profile name; use letters, digits, '-', '_' and '.'")]
+    InvalidName(String),
+    #[error("profile {0:?} has an invalid URL: {1}")]
+    InvalidUrl(String, String),
+    #[error("profile {0:?} has credentials in its URL; give the username and password separately")]
+    CredentialsInUrl(String),
+    #[error("error reading password file {0:?}: {1}")]
+    PasswordFileError(PathBuf, std::io::Error),
+    #[error("password file {0:?} is accessible by other users; chmod 600 it")]
+    InsecurePasswordFile(PathBuf),
+    #[error("error running password command {0:?}: {1}")]
+    PasswordCommandError(String, std::io::Error),
+    #[error("password command {0:?} failed: {1}")]
+    PasswordCommandFailed(String, std::process::ExitStatus),
+    #[error("error reading password: {0}")]
+    PromptError(std::io::Error),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Profiles {
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    /// The device URL, without credentials
+    pub url: String,
+    /// The username, which defaults to `root`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// A file containing the password, which must be readable only by its owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    /// A shell command which prints the password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_command: Option<String>,
+}
+
+/// The directory holding axctl's configuration.
+pub fn config_dir() -> Result<PathBuf, Error> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match std::env::var_os("HOME") {
+            Some(home) if !home.is_empty() => PathBuf::from(home).join(".config"),
+            _ => return Err(Error::NoConfigDir),
+        },
+    };
+    Ok(base.join("axctl"))
+}
+
+impl Profiles {
+    pub fn default_path() -> Result<PathBuf, Error> {
+        Ok(config_dir()?.join("profiles.toml"))
+    }
+
+    /// Load profiles, treating a file which doesn't exist yet as empty.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let s = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(Error::ReadError(path.into(), e)),
+        };
+        toml::from_str(&s).map_err(|e| Error::ParseError(path.into(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let s = toml::to_string(self).expect("profiles serialize");
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| Error::WriteError(path.into(), e))?;
+        }
+        std::fs::write(path, s).map_err(|e| Error::WriteError(path.into(), e))
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Profile, Error> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| Error::UnknownProfile(name.into()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Profile)> {
+        self.profiles.iter().map(|(name, p)| (name.as_str(), p))
+    }
+
+    /// Check that a profile could be added under a name.
+    pub fn check_new(&self, name: &str, profile: &Profile) -> Result<(), Error> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !valid {
+            return Err(Error::InvalidName(name.into()));
+        }
+        if self.profiles.contains_key(name) {
+            return Err(Error::DuplicateProfile(name.into()));
+        }
+        profile.uri(name).map(|_| ())
+    }
+
+    pub fn insert(&mut self, name: &str, profile: Profile) -> Result<(), Error> {
+        self.check_new(name, &profile)?;
+        self.profiles.insert(name.into(), profile);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Profile, Error> {
+        self.profiles
+            .remove(name)
+            .ok_or_else(|| Error::UnknownProfile(name.into()))
+    }
+}
+
+impl Profile {
+    fn uri(&self, name: &str) -> Result<http::Uri, Error> {
+        let uri: http::Uri = self
+            .url
+            .parse()
+            .map_err(|e| Error::InvalidUrl(name.into(), format!("{}", e)))?;
+        match uri.authority() {
+            Some(authority) if authority.as_str().contains('@') => {
+                Err(Error::CredentialsInUrl(name.into()))
+            }
+            Some(_) if uri.scheme().is_some() => Ok(uri),
+            _ => Err(Error::InvalidUrl(
+                name.into(),
+                "expected a URL like http://1.2.3.4/".into(),
+            )),
+        }
+    }
+
+    /// Describe where the password comes from.
+    pub fn password_source(&self) -> String {
+        match (&self.password_file, &self.password_command) {
+            (Some(path), _) => format!("file {}", path.display()),
+            (None, Some(command)) => format!("command {}", command),
+            (None, None) => "prompt".into(),
+        }
+    }
+
+    /// Obtain the password from wherever the profile says it is.
+    pub fn password(&self, name: &str) -> Result<String, Error> {
+        match (&self.password_file, &self.password_command) {
+            (Some(path), _) => read_password_file(path),
+            (None, Some(command)) => run_password_command(command),
+            (None, None) => {
+                prompt_password(&format!("Password for {}: ", name)).map_err(Error::PromptError)
+            }
+        }
+    }
+
+    /// The credentials with which to use the device.
+    pub fn credentials(&self, name: &str) -> Result<Credentials, Error> {
+        Ok(Credentials {
+            username: self.username.as_deref().unwrap_or("root").to_owned(),
+            password: self.password(name)?,
+        })
+    }
+
+    /// The device URL and the credentials with which to use it.
+    pub fn resolve(&self, name: &str) -> Result<(http::Uri, Credentials), Error> {
+        let uri = self.uri(name)?;
+        Ok((uri, self.credentials(name)?))
+    }
+}
+
+fn read_password_file(path: &Path) -> Result<String, Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata =
+            std::fs::metadata(path).map_err(|e| Error::PasswordFileError(path.into(), e))?;
+        if metadata.mode() & 0o077 != 0 {
+            return Err(Error::InsecurePasswordFile(path.into()));
+        }
+    }
+
+    let s = std::fs::read_to_string(path).map_err(|e| Error::PasswordFileError(path.into(), e))?;
+    Ok(first_line(&s))
+}
+
+/// Write a password to a new file which only its owner can read.
+pub fn write_password_file(path: &Path, password: &str) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| Error::PasswordFileError(path.into(), e))?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(path)
+        .map_err(|e| Error::PasswordFileError(path.into(), e))?;
+    writeln!(file, "{}", password).map_err(|e| Error::PasswordFileError(path.into(), e))
+}
+
+fn run_password_command(command: &str) -> Result<String, Error> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| Error::PasswordCommandError(command.into(), e))?;
+    if !output.status.success() {
+        return Err(Error::PasswordCommandFailed(command.into(), output.status));
+    }
+    Ok(first_line(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn first_line(s: &str) -> String {
+    s.lines().next().unwrap_or("").to_owned()
+}
+
+/// Ask for a password on the terminal without echoing it, or read a line from stdin if it's not a
+/// terminal.
+pub fn prompt_password(prompt: &str) -> std::io::Result<String> {
+    use crossterm::event::{read, Event, KeyCode, KeyEvent, KeyModifiers};
+    use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+    use crossterm::tty::IsTty;
+
+    let stdin = std::io::stdin();
+    if !stdin.is_tty() {
+        let mut line = String::new();
+        stdin.read_line(&mut line)?;
+        return Ok(first_line(&line));
+    }
+
+    let to_io = |e: crossterm::ErrorKind| match e {
+        crossterm::ErrorKind::IoError(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::Other, e),
+    };
+
+    let mut stderr = std::io::stderr();
+    write!(stderr, "{}", prompt)?;
+    stderr.flush()?;
+
+    enable_raw_mode().map_err(to_io)?;
+    let mut password = String::new();
+    let result = loop {
+        match read() {
+            Ok(Event::Key(KeyEvent { code, modifiers })) => match code {
+                KeyCode::Enter => break Ok(()),
+                KeyCode::Char('c') | KeyCode::Char('d')
+                    if modifiers.contains(KeyModifiers::CONTROL) =>
+                {
+                    break Err(std::io::Error::new(
+                        std::io::ErrorKind::Interrupted,
+                        "cancelled",
+                    ))
+                }
+                KeyCode::Char(c) => password.push(c),
+                KeyCode::Backspace => {
+                    password.pop();
+                }
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(e) => break Err(to_io(e)),
+        }
+    };
+    disable_raw_mode().map_err(to_io)?;
+    writeln!(stderr)?;
+
+    result.map(|()| password)
+}
@@ -0,0 +1,308 @@
+//! Site files, which declare how devices should be configured.
+//!
+//! ```toml
+//! [parameters]
+//! Network.Bonjour.Enabled = "yes"
+//! "Image.I0.Appearance.Resolution" = "1920x1080"
+//!
+//! [applications.vmd]
+//! version = "4.2.0"
+//! package = "packages/vmd_4_2_0_armv7hf.eap"
+//! state = "running"
+//!
+//! [applications.oldapp]
+//! state = "absent"
+//!
+//! [devices.lobby.parameters]
+//! Network.HostName = "lobby"
+//! ```
+//!
+//! Parameters may be written as dotted keys, quoted names or nested tables, which all mean the
+//! same thing. Sections under `[devices.<name>]` apply only to that device from the inventory, and
+//! take precedence over the rest of the file.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error reading site file {0:?}: {1}")]
+    ReadError(PathBuf, std::io::Error),
+    #[error("error parsing site file {0:?}: {1}")]
+    ParseError(PathBuf, toml::de::Error),
+    #[error("parameter {0:?} must be a string, number or boolean")]
+    InvalidParameter(String),
+}
+
+/// What a site file says a device should look like.
+#[derive(Debug, Clone, Default)]
+pub struct Desired {
+    /// Parameter values by name
+    pub parameters: BTreeMap<String, String>,
+    /// Applications by the name in their `package.conf`
+    pub applications: BTreeMap<String, Application>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Application {
+    /// The version which should be installed, which defaults to the version of `package`
+    pub version: Option<String>,
+    /// The package to install when the application is missing or has another version
+    pub package: Option<PathBuf>,
+    #[serde(default)]
+    pub state: State,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    #[default]
+    Running,
+    Stopped,
+    /// The application should not be installed
+    Absent,
+}
+
+/// The part of a site file which applies to one device.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Section {
+    #[serde(default)]
+    parameters: toml::value::Table,
+    #[serde(default)]
+    applications: BTreeMap<String, Application>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    #[serde(default)]
+    parameters: toml::value::Table,
+    #[serde(default)]
+    applications: BTreeMap<String, Application>,
+    #[serde(default)]
+    devices: BTreeMap<String, Section>,
+}
+
+impl Site {
+    /// Load a site file, taking package paths to be relative to the file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let s = std::fs::read_to_string(path).map_err(|e| Error::ReadError(path.into(), e))?;
+        let mut site: Site = toml::from_str(&s).map_err(|e| Error::ParseError(path.into(), e))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let applications = site.applications.values_mut().chain(
+            site.devices
+                .values_mut()
+                .flat_map(|section| section.applications.values_mut()),
+        );
+        for application in applications {
+            if let Some(package) = &mut application.package {
+                *package = dir.join(&package);
+            }
+        }
+
+        Ok(site)
+    }
+
+    /// The devices which have sections in the site file, but aren't among `devices`.
+    pub fn unmatched_devices<'a>(&'a self, devices: &[&str]) -> Vec<&'a str> {
+        self.devices
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !devices.contains(name))
+            .collect()
+    }
+
+    /// What a device should look like, given its name in the inventory if it has one.
+    pub fn desired(&self, device: Option<&str>) -> Result<Desired, Error> {
+        let mut desired = Desired::default();
+
+        let sections = std::iter::once((&self.parameters, &self.applications)).chain(
+            device
+                .and_then(|d| self.devices.get(d))
+                .map(|section| (&section.parameters, &section.applications)),
+        );
+        for (parameters, applications) in sections {
+            flatten(&mut desired.parameters, None, parameters)?;
+            desired.applications.extend(
+                applications
+                    .iter()
+                    .map(|(name, app)| (name.clone(), app.clone())),
+            );
+        }
+
+        Ok(desired)
+    }
+}
+
+/// Turn nested tables into dotted parameter names.
+fn flatten(
+    parameters: &mut BTreeMap<String, String>,
+    prefix: Option<&str>,
+    table: &toml::value::Table,
+) -> Result<(), Error> {
+    use toml::Value;
+
+    for (key, value) in table {
+        let name = match prefix {
+            Some(prefix) => format!("{}.{}", prefix, key),
+            None => key.clone(),
+        };
+        let value = match value {
+            Value::Table(table) => {
+                flatten(parameters, Some(&name), table)?;
+                continue;
+            }
+            Value::String(s) => s.clone(),
+            Value::Integer(n) => n.to_string(),
+            Value::Float(n) => n.to_string(),
+            // AXIS devices spell booleans this way
+            Value::Boolean(true) => "yes".into(),
+            Value::Boolean(false) => "no".into(),
+            Value::Datetime(_) | Value::Array(_) => return Err(Error::InvalidParameter(name)),
+        };
+        let name = match name.strip_prefix("root.") {
+            Some(name) => name.to_owned(),
+            None => name,
+        };
+        parameters.insert(name, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(s: &str) -> Site {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn ways_of_writing_parameters_agree() {
+        let expected: BTreeMap<String, String> = vec![
+            ("Image.I0.Appearance.Resolution", "1920x1080"),
+            ("Network.Bonjour.Enabled", "yes"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+
+        let sites = [
+            "[parameters]\n\
+             Network.Bonjour.Enabled = \"yes\"\n\
+             Image.I0.Appearance.Resolution = \"1920x1080\"\n",
+            "[parameters]\n\
+             \"Network.Bonjour.Enabled\" = \"yes\"\n\
+             \"root.Image.I0.Appearance.Resolution\" = \"1920x1080\"\n",
+            "[parameters.Network.Bonjour]\n\
+             Enabled = \"yes\"\n\
+             [parameters.Image.I0]\n\
+             Appearance = { Resolution = \"1920x1080\" }\n",
+            "[parameters]\n\
+             Network = { Bonjour.Enabled = \"yes\" }\n\
+             \"Image.I0\".Appearance.Resolution = \"1920x1080\"\n",
+        ];
+        for s in &sites {
+            assert_eq!(site(s).desired(None).unwrap().parameters, expected, "{}", s);
+        }
+    }
+
+    #[test]
+    fn parameter_values() {
+        let desired = site(
+            "[parameters]\n\
+             Network.Bonjour.Enabled = true\n\
+             Network.UPnP.Enabled = false\n\
+             Image.I0.Stream.FPS = 15\n\
+             Image.I0.Appearance.Compression = 0.5\n",
+        )
+        .desired(None)
+        .unwrap();
+        assert_eq!(desired.parameters["Network.Bonjour.Enabled"], "yes");
+        assert_eq!(desired.parameters["Network.UPnP.Enabled"], "no");
+        assert_eq!(desired.parameters["Image.I0.Stream.FPS"], "15");
+        assert_eq!(desired.parameters["Image.I0.Appearance.Compression"], "0.5");
+
+        let result = site("[parameters]\nNetwork.DNSServers = [\"10.0.0.1\"]\n").desired(None);
+        assert!(
+            matches!(result, Err(Error::InvalidParameter(name)) if name == "Network.DNSServers")
+        );
+    }
+
+    #[test]
+    fn device_sections_take_precedence() {
+        let site = site(
+            "[parameters]\n\
+             Network.HostName = \"camera\"\n\
+             Network.Bonjour.Enabled = \"yes\"\n\
+             [applications.vmd]\n\
+             state = \"running\"\n\
+             [devices.lobby.parameters]\n\
+             Network.HostName = \"lobby\"\n\
+             [devices.lobby.applications.vmd]\n\
+             state = \"stopped\"\n",
+        );
+
+        let lobby = site.desired(Some("lobby")).unwrap();
+        assert_eq!(lobby.parameters["Network.HostName"], "lobby");
+        assert_eq!(lobby.parameters["Network.Bonjour.Enabled"], "yes");
+        assert_eq!(lobby.applications["vmd"].state, State::Stopped);
+
+        for device in &[Some("dock"), None] {
+            let desired = site.desired(*device).unwrap();
+            assert_eq!(desired.parameters["Network.HostName"], "camera");
+            assert_eq!(desired.applications["vmd"].state, State::Running);
+        }
+    }
+
+    #[test]
+    fn unmatched_devices() {
+        let site = site(
+            "[devices.lobby.parameters]\n\
+             Network.HostName = \"lobby\"\n\
+             [devices.dcok.parameters]\n\
+             Network.HostName = \"dock\"\n",
+        );
+        assert_eq!(site.unmatched_devices(&["lobby", "dock"]), vec!["dcok"]);
+        assert_eq!(site.unmatched_devices(&[]), vec!["dcok", "lobby"]);
+    }
+
+    #[test]
+    fn load_resolves_packages() {
+        let dir = std::env::temp_dir().join(format!("axctl-site-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("site.toml");
+        std::fs::write(
+            &path,
+            "[applications.vmd]\n\
+             package = \"packages/vmd.eap\"\n\
+             [devices.lobby.applications.vmd]\n\
+             package = \"/opt/vmd.eap\"\n",
+        )
+        .unwrap();
+
+        let site = Site::load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let site = site.unwrap();
+
+        let package = |device| {
+            site.desired(device).unwrap().applications["vmd"]
+                .package
+                .clone()
+        };
+        assert_eq!(package(None), Some(dir.join("packages/vmd.eap")));
+        assert_eq!(package(Some("lobby")), Some(PathBuf::from("/opt/vmd.eap")));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(toml::from_str::<Site>("[parameter]\nNetwork.HostName = \"lobby\"\n").is_err());
+        assert!(toml::from_str::<Site>("[applications.vmd]\nstate = \"paused\"\n").is_err());
+        assert!(toml::from_str::<Site>("[devices.lobby.parameter]\nA = \"b\"\n").is_err());
+    }
+}
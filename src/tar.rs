@@ -1,4 +1,6 @@
 use std::borrow::Borrow;
+use std::io::Write;
+use std::path::Path;
 use std::time::SystemTime;
 
 pub fn build<'a, I: IntoIterator<Item = F>, F: Borrow<File>>(files: I) -> Vec<u8> {
@@ -40,6 +42,60 @@ pub fn build<'a, I: IntoIterator<Item = F>, F: Borrow<File>>(files: I) -> Vec<u8
     bytes
 }
 
+/// Build a gzipped tar of the file or directory at `path`, stored in the archive as `name`.
+///
+/// Permissions are preserved but, as with `build()`, everything is owned by root.
+pub fn archive_path(name: &str, path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut gz = deflate::write::GzEncoder::new(
+        std::io::Cursor::new(&mut bytes),
+        deflate::Compression::Default,
+    );
+    let mut tar = ::tar::Builder::new(&mut gz);
+
+    append_path(&mut tar, Path::new(name), path)?;
+
+    tar.finish()?;
+    std::mem::drop(tar);
+    gz.finish()?;
+
+    bytes.shrink_to_fit();
+    Ok(bytes)
+}
+
+fn append_path<W: Write>(
+    tar: &mut ::tar::Builder<W>,
+    name: &Path,
+    path: &Path,
+) -> std::io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&metadata, tar::HeaderMode::Complete);
+    header.set_uid(0);
+    header.set_gid(0);
+
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        header.set_link_name(std::fs::read_link(path)?)?;
+        tar.append_data(&mut header, name, std::io::empty())
+    } else if file_type.is_dir() {
+        tar.append_data(&mut header, name, std::io::empty())?;
+
+        let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            append_path(tar, &name.join(entry.file_name()), &entry.path())?;
+        }
+        Ok(())
+    } else if file_type.is_file() {
+        tar.append_data(&mut header, name, std::fs::File::open(path)?)
+    } else {
+        // Devices, sockets and FIFOs can't be meaningfully copied
+        Ok(())
+    }
+}
+
 pub fn file<P: AsRef<str>, B: AsRef<[u8]>>(path: P, bytes: B) -> File {
     file_with_mode(path, bytes, 0o644)
 }